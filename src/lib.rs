@@ -1,159 +1,8330 @@
 use async_trait::async_trait;
-use futures::{stream::BoxStream, StreamExt};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::{BoxFuture, LocalBoxFuture},
+    stream::{BoxStream, LocalBoxStream},
+    FutureExt, Stream, StreamExt,
+};
 use std::{
-    collections::HashMap,
+    any::Any,
+    borrow::Cow,
+    cell::RefCell,
+    collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet, VecDeque},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io::{self, Write},
+    mem,
+    ops::{Bound, ControlFlow, Deref, DerefMut, RangeBounds},
+    panic,
+    pin::Pin,
+    rc::Rc,
     result::Result,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomicOrdering},
+        Arc, Mutex, MutexGuard, TryLockError, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{oneshot, watch, Notify},
+    task::JoinHandle,
+    time::{self, Instant},
 };
 
 type City = String;
 type Temperature = u64;
 
+/// The most queued stream updates applied per lock acquisition.
+const APPLY_BATCH: usize = 64;
+
+/// The sweeper shrinks the map once it uses less than 1/COMPACT_RATIO of its
+/// capacity.
+const COMPACT_RATIO: usize = 4;
+
+/// How many errors `StreamCache::recent_errors` remembers.
+const ERROR_HISTORY: usize = 16;
+
+/// How many time buckets split the `windowed_hit_ratio` window.
+const HIT_WINDOW_BUCKETS: u32 = 10;
+
+/// The first wait before resubscribing, unless `initial_fetch_retries` sets
+/// a backoff.
+const RESUBSCRIBE_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The longest a retry or resubscribe waits.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// One item of a subscription stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Update {
+    Single(City, Temperature),
+    /// A value that expires after its own TTL instead of the cache-wide one.
+    Expiring(City, Temperature, Duration),
+    /// Several values applied together under a single lock acquisition (one
+    /// per shard with `StreamCacheBuilder::shards`).
+    Batch(Vec<(City, Temperature)>),
+    /// Discards every entry and fetches again, e.g. after an upstream gap.
+    /// Updates streamed after it are applied once the fetch is in.
+    Resync,
+    /// Ends the leading snapshot of a stream; see
+    /// `StreamCacheBuilder::snapshot_from_subscribe`.
+    SnapshotComplete,
+}
+
+impl Update {
+    /// The entries this update writes, with their TTLs.
+    fn into_entries(self) -> Vec<(City, Temperature, Option<Duration>)> {
+        match self {
+            Update::Single(city, temperature) => vec![(city, temperature, None)],
+            Update::Expiring(city, temperature, ttl) => vec![(city, temperature, Some(ttl))],
+            Update::Batch(batch) => batch
+                .into_iter()
+                .map(|(city, temperature)| (city, temperature, None))
+                .collect(),
+            Update::Resync | Update::SnapshotComplete => Vec::new(),
+        }
+    }
+}
+
 #[async_trait]
 pub trait Api: Send + Sync + 'static + Clone {
     async fn fetch(&self) -> Result<HashMap<City, Temperature>, String>;
     async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>>;
+
+    /// Streams only `keys`, when the cache was given an interest set with
+    /// `StreamCacheBuilder::interest`. Override this to filter upstream; the
+    /// default streams everything via `subscribe`.
+    async fn subscribe_keys(
+        &self,
+        keys: &[City],
+    ) -> BoxStream<Result<(City, Temperature), String>> {
+        let _ = keys;
+        self.subscribe().await
+    }
+
+    /// The stream the cache actually consumes. Override this to deliver
+    /// batches; the default wraps each `subscribe` item in `Update::Single`.
+    async fn subscribe_updates(&self) -> BoxStream<Result<Update, String>> {
+        self.subscribe()
+            .await
+            .map(|item| item.map(|(city, temperature)| Update::Single(city, temperature)))
+            .boxed()
+    }
+
+    /// One page of a paginated snapshot, starting at `cursor` (`None` for
+    /// the first page), with the cursor of the next page if there is one.
+    /// Override this for an upstream that paginates; the default returns all
+    /// of `fetch` as a single page.
+    async fn fetch_page(
+        &self,
+        cursor: Option<String>,
+    ) -> Result<(HashMap<City, Temperature>, Option<String>), CacheError> {
+        let _ = cursor;
+        let fetched = self.fetch().await.map_err(CacheError::Fetch)?;
+        Ok((fetched, None))
+    }
+
+    /// The initial snapshot the cache actually applies. Override this to
+    /// attach per-item TTLs, or to return an ordered list that may repeat a
+    /// key; repeats are resolved with the conflict policy, later items
+    /// counting as newer. The default follows `fetch_page` until the cursor
+    /// runs out and wraps each entry in `Update::Single`.
+    async fn fetch_updates(&self) -> Result<Vec<Update>, String> {
+        let mut updates = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (page, next) = self.fetch_page(cursor).await.map_err(|err| match err {
+                CacheError::Fetch(err) => err,
+                err => err.to_string(),
+            })?;
+            updates.extend(
+                page.into_iter()
+                    .map(|(city, temperature)| Update::Single(city, temperature)),
+            );
+            match next {
+                Some(next) => cursor = Some(next),
+                None => return Ok(updates),
+            }
+        }
+    }
 }
 
-pub struct StreamCache {
-    results: Arc<Mutex<HashMap<String, u64>>>,
+/// Pulls a single snapshot from `api`, following its `fetch_page` cursor,
+/// for callers that need the current values once and no cache to keep them
+/// up to date.
+pub async fn fetch_once(api: &impl Api) -> Result<HashMap<String, u64>, CacheError> {
+    let mut snapshot = HashMap::new();
+    let mut cursor = None;
+    loop {
+        let (page, next) = api.fetch_page(cursor).await?;
+        snapshot.extend(page);
+        match next {
+            Some(next) => cursor = Some(next),
+            None => return Ok(snapshot),
+        }
+    }
 }
 
-impl StreamCache {
-    pub fn new(api: impl Api) -> Self {
-        let instance = Self {
-            results: Arc::new(Mutex::new(HashMap::new())),
-        };
-        instance.update_in_background(api);
-        instance
+/// A secondary store consulted by `get_or_fallback` on a miss, e.g. Redis.
+#[async_trait]
+pub trait Fallback: Send + Sync + 'static {
+    async fn get(&self, key: &str) -> Option<u64>;
+}
+
+/// One step of `StreamCache::transaction`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Set(String, u64),
+    Remove(String),
+}
+
+/// One streamed entry on its way through `StreamCacheBuilder::with_middleware`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateContext {
+    pub key: String,
+    pub value: u64,
+    /// The `add_source` id, or `None` for `update_in_background`.
+    pub source: Option<SourceId>,
+}
+
+/// An async step run on every streamed entry before it is stored, e.g. to
+/// forward updates to a message bus. It may change `ctx`, or veto the entry
+/// with `ControlFlow::Break`, which also skips the rest of the chain.
+#[async_trait]
+pub trait UpdateMiddleware: Send + Sync + 'static {
+    async fn on_update(&self, ctx: &mut UpdateContext) -> ControlFlow<()>;
+}
+
+/// A span in progress, ended once the last clone is dropped. Without the
+/// `opentelemetry` feature no span is ever started.
+#[derive(Clone)]
+#[cfg_attr(not(feature = "opentelemetry"), allow(dead_code))]
+struct Span {
+    /// Has the span as its active span, to start children under.
+    #[cfg(feature = "opentelemetry")]
+    context: opentelemetry::Context,
+}
+
+impl Span {
+    /// Ends the span here rather than where it goes out of scope.
+    fn end(self) {}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheError {
+    /// The cache lock was poisoned by a panicking writer.
+    Poisoned,
+    /// `Api::fetch` failed.
+    Fetch(String),
+    /// The subscription stream yielded an error.
+    Subscribe(String),
+    /// `Api::subscribe` panicked, with the panic message.
+    SubscribePanic(String),
+    /// A value fell outside the bounds set with `with_value_bounds`.
+    OutOfRange { key: String, value: u64 },
+    /// A value arrived after the `with_write_deadline` cutoff.
+    PastDeadline { key: String, value: u64 },
+    /// The cache was still empty when `empty_warn_after` ran out.
+    NoData,
+    /// The subscription sent nothing for the configured idle timeout.
+    Idle(Duration),
+    /// A `CacheGroup` already holds as many caches as its limit allows.
+    GroupFull,
+    /// `take_dirty` was called without `StreamCacheBuilder::track_dirty`.
+    DirtyTrackingOff,
+    /// `range` was called on a cache whose store keeps no key order.
+    Unordered,
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::Poisoned => write!(f, "cache lock poisoned"),
+            CacheError::Fetch(err) => write!(f, "fetch failed: {err}"),
+            CacheError::Subscribe(err) => write!(f, "subscription error: {err}"),
+            CacheError::SubscribePanic(err) => write!(f, "subscribe panicked: {err}"),
+            CacheError::OutOfRange { key, value } => {
+                write!(f, "value {value} for {key} is out of range")
+            }
+            CacheError::PastDeadline { key, value } => {
+                write!(
+                    f,
+                    "value {value} for {key} arrived after the write deadline"
+                )
+            }
+            CacheError::NoData => write!(f, "no data received from any source"),
+            CacheError::Idle(idle) => write!(f, "subscription idle for {idle:?}"),
+            CacheError::GroupFull => write!(f, "cache group is full"),
+            CacheError::DirtyTrackingOff => write!(f, "dirty tracking is not enabled"),
+            CacheError::Unordered => write!(f, "the store does not support range queries"),
+        }
     }
+}
 
-    pub fn get(&self, key: &str) -> Option<u64> {
-        let results = self.results.lock().expect("poisoned");
-        results.get(key).copied()
+impl std::error::Error for CacheError {}
+
+/// Which of a worker's initial fetch and its stream applies first when both
+/// are ready; see `StreamCacheBuilder::write_priority`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WritePriority {
+    /// Streamed updates wait until the initial fetch is applied.
+    FetchFirst,
+    /// Both run at once, but a fetched value never replaces one the stream
+    /// (or an insert) wrote, so streamed values win whatever the timing.
+    SubscribeFirst,
+    /// Whichever takes the lock first.
+    #[default]
+    Fair,
+}
+
+/// What `get` and the background worker do when they find the lock poisoned.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PoisonPolicy {
+    /// Panic, as a poisoned lock usually means a bug.
+    #[default]
+    Panic,
+    /// Ignore the poison and keep using the data.
+    Recover,
+    /// Report `CacheError::Poisoned`; the worker stops applying updates.
+    Error,
+}
+
+/// What a worker does when its subscription stream yields an error or stalls
+/// past the idle timeout. The error is reported to the error handler in every
+/// case.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionErrorPolicy {
+    /// Skip the item and keep reading the stream.
+    #[default]
+    Skip,
+    /// Drop the stream and subscribe again, after a delay that doubles with
+    /// every consecutive failure (capped at 30 seconds) and resets once an
+    /// update arrives. The first delay is the `initial_fetch_retries`
+    /// backoff, or 100ms if that is unset.
+    Reconnect,
+    /// Stop the worker; `last_error` returns the error.
+    Fail,
+}
+
+impl SubscriptionErrorPolicy {
+    /// The policy that applies to `err`: after a panicking subscribe there is
+    /// no stream to skip ahead in, so `Skip` reconnects as well.
+    fn for_error(self, err: &CacheError) -> Self {
+        match (self, err) {
+            (SubscriptionErrorPolicy::Skip, CacheError::SubscribePanic(_)) => {
+                SubscriptionErrorPolicy::Reconnect
+            }
+            (policy, _) => policy,
+        }
     }
+}
 
-    pub fn update_in_background(&self, api: impl Api + 'static) {
-        let results = Arc::clone(&self.results);
+impl PoisonPolicy {
+    fn lock<T>(self, mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, CacheError> {
+        match mutex.lock() {
+            Ok(guard) => Ok(guard),
+            Err(poisoned) => match self {
+                PoisonPolicy::Panic => panic!("poisoned"),
+                PoisonPolicy::Recover => Ok(poisoned.into_inner()),
+                PoisonPolicy::Error => Err(CacheError::Poisoned),
+            },
+        }
+    }
+}
 
-        tokio::spawn(async move {
-            // Start subscribing to updates
-            let mut subscription = api.subscribe().await;
+/// A single change to a cached value, as seen by `watch` subscribers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub key: String,
+    /// The previous value, or `None` if the key was new (or is part of an
+    /// initial snapshot).
+    pub old: Option<u64>,
+    pub new: u64,
+}
 
-            // Spawn a task to handle the fetch operation
-            let fetch_results = Arc::clone(&results);
-            let fetch_api = api.clone();
-            let fetch_handle = tokio::spawn(async move {
-                if let Ok(fetched_data) = fetch_api.fetch().await {
-                    let mut results_lock = fetch_results.lock().unwrap();
-                    for (city, temp) in fetched_data {
-                        results_lock.entry(city).or_insert(temp);
-                    }
-                }
-            });
+/// A stream of `ChangeEvent`s returned by `StreamCache::watch`. Dropping it
+/// unregisters it from the cache.
+pub struct Watch {
+    events: UnboundedReceiver<ChangeEvent>,
+    inner: Weak<Inner>,
+}
 
-            // Process subscription updates
-            while let Some(update) = subscription.next().await {
-                if let Ok((city, temperature)) = update {
-                    let mut results_lock = results.lock().unwrap();
-                    results_lock.insert(city, temperature);
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.events.close();
+        if let Some(inner) = self.inner.upgrade() {
+            for shard in inner.shards.iter() {
+                if let Ok(mut state) = shard.lock() {
+                    state.watchers.retain(|watcher| !watcher.is_closed());
                 }
             }
+        }
+    }
+}
 
-            // Ensure the fetch operation completes
-            let _ = fetch_handle.await;
-        });
+impl Stream for Watch {
+    type Item = ChangeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ChangeEvent>> {
+        self.events.poll_next_unpin(cx)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::time::Duration;
-    use tokio::sync::Notify;
-    use tokio::time;
+/// A stream of coalesced change batches; see `StreamCache::watch_batched`.
+pub struct BatchedWatch {
+    batches: BoxStream<'static, Vec<ChangeEvent>>,
+}
 
-    use futures::{future, stream::select, FutureExt, StreamExt};
-    use maplit::hashmap;
+impl BatchedWatch {
+    fn new(events: Watch, ticker: time::Interval) -> Self {
+        let batches = futures::stream::unfold(
+            (events, ticker, true),
+            |(mut events, mut ticker, open)| async move {
+                if !open {
+                    return None;
+                }
+                loop {
+                    ticker.tick().await;
+                    let mut batch: Vec<ChangeEvent> = Vec::new();
+                    let mut open = true;
+                    while let Some(event) = events.next().now_or_never() {
+                        let Some(event) = event else {
+                            open = false;
+                            break;
+                        };
+                        match batch.iter_mut().find(|pending| pending.key == event.key) {
+                            Some(pending) => pending.new = event.new,
+                            None => batch.push(event),
+                        }
+                    }
+                    if !batch.is_empty() {
+                        return Some((batch, (events, ticker, open)));
+                    }
+                    if !open {
+                        return None;
+                    }
+                }
+            },
+        )
+        .boxed();
+        BatchedWatch { batches }
+    }
+}
 
-    use super::*;
+impl Stream for BatchedWatch {
+    type Item = Vec<ChangeEvent>;
 
-    #[derive(Default, Clone)]
-    struct TestApi {
-        signal: Arc<Notify>,
-        fetch_error: Option<String>,
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<ChangeEvent>>> {
+        self.batches.poll_next_unpin(cx)
     }
+}
 
-    #[async_trait]
-    impl Api for TestApi {
-        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
-            // Simulate error if fetch_error is set
-            if let Some(err) = self.fetch_error.clone() {
-                return Err(err);
-            }
-            // fetch is slow and may get delayed until after we receive the first updates
-            self.signal.notified().await;
-            Ok(hashmap! {
-                "Berlin".to_string() => 29,
-                "Paris".to_string() => 31,
-            })
+/// Identifies one of several sources merged into the same cache.
+pub type SourceId = String;
+
+/// A custom conflict resolver, called with the key, the existing value and
+/// the incoming value.
+pub type Resolver = Arc<dyn Fn(&str, u64, u64) -> u64 + Send + Sync>;
+
+/// Called with the key and last value of an entry removed by its TTL.
+pub type ExpireCallback = Arc<dyn Fn(&str, u64) + Send + Sync>;
+
+/// Receives errors hit by the background worker, with how many identical
+/// occurrences the call stands for (see `StreamCacheBuilder::error_dedup_window`).
+pub type ErrorHandler = Arc<dyn Fn(&CacheError, usize) + Send + Sync>;
+
+/// Maps every key to its canonical form; see
+/// `StreamCacheBuilder::with_key_normalizer`.
+pub type KeyNormalizer = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Ages a value read from the cache, called with the stored value and the
+/// time since it was last updated; see `StreamCacheBuilder::with_decay`.
+pub type DecayFn = Arc<dyn Fn(u64, Duration) -> u64 + Send + Sync>;
+
+/// The minimum interval between applied updates for a key, or `None` to leave
+/// it unthrottled; see `StreamCacheBuilder::with_per_key_throttle`.
+pub type ThrottleFn = Arc<dyn Fn(&str) -> Option<Duration> + Send + Sync>;
+
+/// Folds an applied update into a running aggregate, called with the
+/// aggregate so far, the key, its old value (if any) and its new value; see
+/// `StreamCacheBuilder::with_aggregate`.
+pub type AggregateFn<A> = Arc<dyn Fn(&A, &str, Option<u64>, u64) -> A + Send + Sync>;
+
+/// Receives one JSON object (without a trailing newline) per applied update.
+pub type LogCallback = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// `ConflictPolicy::Async`'s resolver, called with the key, the existing
+/// value and the incoming value.
+pub type AsyncResolver = Arc<dyn Fn(&str, u64, u64) -> BoxFuture<'static, u64> + Send + Sync>;
+
+/// Decides the stored value when an incoming update meets an existing entry.
+#[derive(Clone, Default)]
+pub enum ConflictPolicy {
+    /// Streamed updates overwrite; a fetch only fills keys not yet written.
+    #[default]
+    LastWriteWins,
+    KeepMax,
+    KeepMin,
+    Custom(Resolver),
+    /// Awaits the resolver, e.g. to ask an external service which source is
+    /// authoritative. Only streamed updates await it, one at a time and
+    /// without the lock held, so a slow resolver throttles its stream; they
+    /// are no longer batched per lock. Inserts and fetches cannot wait and
+    /// fall back to `LastWriteWins`.
+    Async(AsyncResolver),
+}
+
+impl ConflictPolicy {
+    fn resolve(&self, key: &str, existing: u64, incoming: u64, origin: Origin) -> u64 {
+        match self {
+            // An `Async` stream value arrives here already resolved.
+            ConflictPolicy::LastWriteWins | ConflictPolicy::Async(_) => match origin {
+                Origin::Fetch => existing,
+                Origin::Stream => incoming,
+            },
+            ConflictPolicy::KeepMax => existing.max(incoming),
+            ConflictPolicy::KeepMin => existing.min(incoming),
+            ConflictPolicy::Custom(resolve) => resolve(key, existing, incoming),
         }
+    }
+}
 
-        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
-            let results = vec![
-                Ok(("London".to_string(), 27)),
-                Ok(("Paris".to_string(), 32)),
-                // Simulate an error for demonstration purposes
-                Err("Subscription error".to_string()),
-            ];
-            select(
-                futures::stream::iter(results),
-                async {
-                    self.signal.notify_one();
-                    future::pending().await
-                }
-                .into_stream(),
-            )
-            .boxed()
+/// What a manual insert did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// The key was new.
+    Inserted,
+    /// The key existed and now holds the policy's result.
+    Updated { old: u64 },
+    /// The conflict policy kept the existing value.
+    RejectedByPolicy { kept: u64 },
+    /// The key was new, and `key` was evicted to stay within capacity.
+    Evicted { key: String },
+    /// The value was discarded before reaching the conflict policy, e.g. for
+    /// being out of bounds or the sentinel.
+    Dropped,
+    /// The per-key throttle held the value back; the latest held value is
+    /// applied once the key's interval has passed.
+    Deferred,
+}
+
+impl InsertOutcome {
+    /// The value the key held before, as `StreamCache::insert` returns it.
+    fn previous(self) -> Option<u64> {
+        match self {
+            InsertOutcome::Inserted
+            | InsertOutcome::Evicted { .. }
+            | InsertOutcome::Dropped
+            | InsertOutcome::Deferred => None,
+            InsertOutcome::Updated { old } => Some(old),
+            InsertOutcome::RejectedByPolicy { kept } => Some(kept),
         }
     }
+}
 
-    #[tokio::test]
-    async fn works() {
-        let cache = StreamCache::new(TestApi::default());
+/// Where an incoming value came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Origin {
+    Fetch,
+    Stream,
+}
 
-        // Allow cache to update
-        time::sleep(Duration::from_millis(100)).await;
+struct EntryMeta {
+    source: Option<SourceId>,
+    /// Whether the value was fetched or streamed (inserts count as
+    /// streamed), for `WritePriority::SubscribeFirst`.
+    origin: Origin,
+    expires_at: Option<Instant>,
+    /// The TTL `expires_at` was computed from, for sliding expiry.
+    ttl: Option<Duration>,
+    /// When the value was last written, or confirmed by an identical update.
+    updated_at: Instant,
+    /// `State::clock` at the last read or write, for LRU eviction.
+    last_used: u64,
+    /// `State::clock` at the last write, ordering `recent`.
+    written: u64,
+    /// How many times the value was written, for `hot_keys`.
+    updates: u64,
+}
 
-        assert_eq!(cache.get("Berlin"), Some(29));
-        assert_eq!(cache.get("London"), Some(27));
-        assert_eq!(cache.get("Paris"), Some(32));
+impl EntryMeta {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
     }
 
-    #[tokio::test]
-    async fn test_subscription_error_handling() {
-        let api = TestApi::default();
-        let cache = StreamCache::new(api.clone());
+    /// Whether the entry is past its TTL and `grace` on top of it.
+    fn is_gone(&self, now: Instant, grace: Duration) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at + grace <= now)
+    }
+}
 
-        time::sleep(Duration::from_millis(100)).await;
+/// The map holding a cache's values; see `StreamCacheBuilder::store`.
+/// Expiry, eviction and watchers stay in the cache, a store only keeps
+/// key/value pairs.
+pub trait Store: Send {
+    fn get(&self, key: &str) -> Option<u64>;
+    fn get_mut(&mut self, key: &str) -> Option<&mut u64>;
+    fn insert(&mut self, key: String, value: u64) -> Option<u64>;
+    fn remove(&mut self, key: &str) -> Option<u64>;
+    fn len(&self) -> usize;
+    fn iter(&self) -> Box<dyn Iterator<Item = (&String, u64)> + '_>;
+    fn retain(&mut self, keep: &mut dyn FnMut(&str, u64) -> bool);
+    fn clear(&mut self);
 
-        assert_eq!(cache.get("London"), Some(27));
-        assert_eq!(cache.get("Paris"), Some(32));
-        time::sleep(Duration::from_millis(100)).await;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-        assert_eq!(cache.get("London"), Some(27));
+    fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
     }
 
-    #[tokio::test]
-    async fn test_missing_keys() {
-        let cache = StreamCache::new(TestApi::default());
-        assert_eq!(cache.get("NotExist"), None);
+    fn snapshot(&self) -> HashMap<String, u64> {
+        self.iter()
+            .map(|(key, value)| (key.clone(), value))
+            .collect()
     }
 
-    #[tokio::test]
-    async fn test_initial_state() {
-        let cache = StreamCache::new(TestApi::default());
-        assert_eq!(cache.get("Berlin"), None);
-        assert_eq!(cache.get("London"), None);
-        assert_eq!(cache.get("Paris"), None);
+    /// The entries between `from` and `to` in key order, or `None` if the
+    /// store is unordered.
+    fn range(&self, from: Bound<&str>, to: Bound<&str>) -> Option<Vec<(String, u64)>> {
+        let _ = (from, to);
+        None
+    }
+
+    /// Allocated slots, for stores that over-allocate.
+    fn capacity(&self) -> usize {
+        self.len()
+    }
+
+    fn shrink_to_fit(&mut self) {}
+
+    /// A new, empty store of the same kind, for the extra shards of
+    /// `StreamCacheBuilder::shards`. Defaults to a `HashMap`.
+    fn empty(&self) -> Box<dyn Store> {
+        Box::new(HashMap::new())
+    }
+}
+
+impl Default for Box<dyn Store> {
+    fn default() -> Self {
+        Box::new(HashMap::new())
+    }
+}
+
+impl Store for HashMap<String, u64> {
+    fn get(&self, key: &str) -> Option<u64> {
+        HashMap::get(self, key).copied()
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut u64> {
+        HashMap::get_mut(self, key)
+    }
+
+    fn insert(&mut self, key: String, value: u64) -> Option<u64> {
+        HashMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<u64> {
+        HashMap::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&String, u64)> + '_> {
+        Box::new(HashMap::iter(self).map(|(key, value)| (key, *value)))
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&str, u64) -> bool) {
+        HashMap::retain(self, |key, value| keep(key, *value));
+    }
+
+    fn clear(&mut self) {
+        HashMap::clear(self);
+    }
+
+    fn capacity(&self) -> usize {
+        HashMap::capacity(self)
+    }
+
+    fn shrink_to_fit(&mut self) {
+        HashMap::shrink_to_fit(self);
+    }
+}
+
+/// A `Store` kept in key order, so `StreamCache::range` can answer range
+/// queries.
+#[derive(Debug, Default)]
+pub struct BTreeStore(BTreeMap<String, u64>);
+
+impl Store for BTreeStore {
+    fn get(&self, key: &str) -> Option<u64> {
+        self.0.get(key).copied()
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut u64> {
+        self.0.get_mut(key)
+    }
+
+    fn insert(&mut self, key: String, value: u64) -> Option<u64> {
+        self.0.insert(key, value)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<u64> {
+        self.0.remove(key)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&String, u64)> + '_> {
+        Box::new(self.0.iter().map(|(key, value)| (key, *value)))
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&str, u64) -> bool) {
+        self.0.retain(|key, value| keep(key, *value));
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    fn range(&self, from: Bound<&str>, to: Bound<&str>) -> Option<Vec<(String, u64)>> {
+        // `BTreeMap::range` panics on an inverted range; treat it as empty.
+        let inverted = match (from, to) {
+            (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) => start > end,
+            _ => false,
+        };
+        if inverted {
+            return Some(Vec::new());
+        }
+        let entries = self
+            .0
+            .range::<str, _>((from, to))
+            .map(|(key, value)| (key.clone(), *value));
+        Some(entries.collect())
+    }
+
+    fn empty(&self) -> Box<dyn Store> {
+        Box::new(BTreeStore::default())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    values: Box<dyn Store>,
+    meta: HashMap<String, EntryMeta>,
+    watchers: Vec<UnboundedSender<ChangeEvent>>,
+    /// Shared by every shard, so ticks order reads and writes cache-wide.
+    clock: Arc<AtomicU64>,
+    /// Entries removed for having expired, reported once the lock is released.
+    expired: Vec<(String, u64)>,
+    /// Worker errors, reported once the lock is released.
+    errors: Vec<CacheError>,
+    /// Whether to record `applied` for the JSON update log.
+    log_applied: bool,
+    /// Whether reads restart an entry's TTL.
+    sliding_ttl: bool,
+    /// How long expired entries stay readable as stale.
+    stale_grace: Duration,
+    /// Set when a stale read wants a refresh, started once the lock is
+    /// released.
+    revalidate: bool,
+    /// The JSON update log's sequence number, shared by every shard.
+    seq: Arc<AtomicU64>,
+    applied: Vec<AppliedUpdate>,
+    /// Changes held back by flap suppression, per key.
+    flaps: HashMap<String, PendingChange>,
+    /// The latest update held back by the per-key throttle, per key.
+    throttled: HashMap<String, Throttled>,
+    metrics: Metrics,
+    last_error: Option<CacheError>,
+    /// Whether to record `dirty`, with `StreamCacheBuilder::track_dirty`.
+    track_dirty: bool,
+    /// Keys changed since the last `take_dirty`.
+    dirty: HashSet<String>,
+    /// Keys `pin` exempted from eviction.
+    pinned: HashSet<String>,
+    hit_window: HitWindow,
+    /// The latest `ERROR_HISTORY` errors, oldest first.
+    error_history: VecDeque<(Instant, CacheError)>,
+    /// Shared by every shard, and locked inside a shard's lock.
+    aggregate: Option<Arc<Mutex<Aggregate>>>,
+}
+
+/// A `with_aggregate` value with its fold, type-erased so `State` needs no
+/// type parameter.
+struct Aggregate {
+    value: Box<dyn Any + Send>,
+    fold: ErasedFold,
+}
+
+type ErasedFold = Box<dyn Fn(&dyn Any, &str, Option<u64>, u64) -> Box<dyn Any + Send> + Send>;
+
+/// Counters returned by `StreamCache::metrics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Reads that found a live entry.
+    pub hits: u64,
+    /// Reads that found nothing.
+    pub misses: u64,
+    /// Writes that changed a value.
+    pub updates: u64,
+    /// Errors reported by the workers or by `insert`.
+    pub errors: u64,
+    /// Time spent waiting for the cache lock, with
+    /// `StreamCacheBuilder::measure_lock_contention`.
+    pub lock_wait_total: Duration,
+    /// The longest single wait for the cache lock.
+    pub lock_wait_max: Duration,
+}
+
+impl Metrics {
+    /// Adds up the counters of two shards.
+    fn merge(self, other: Metrics) -> Metrics {
+        Metrics {
+            hits: self.hits + other.hits,
+            misses: self.misses + other.misses,
+            updates: self.updates + other.updates,
+            errors: self.errors + other.errors,
+            lock_wait_total: self.lock_wait_total + other.lock_wait_total,
+            lock_wait_max: self.lock_wait_max.max(other.lock_wait_max),
+        }
+    }
+}
+
+/// Hits and misses over a sliding window, counted in time buckets that
+/// expire as the window moves on.
+#[derive(Default)]
+struct HitWindow {
+    window: Duration,
+    bucket: Duration,
+    /// Each bucket's start, hits and misses, oldest first.
+    buckets: VecDeque<(Instant, u64, u64)>,
+}
+
+impl HitWindow {
+    fn new(window: Duration) -> Self {
+        HitWindow {
+            window,
+            bucket: (window / HIT_WINDOW_BUCKETS).max(Duration::from_millis(1)),
+            buckets: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, hit: bool, now: Instant) {
+        let current = self.buckets.back().map(|bucket| bucket.0);
+        if current.is_none_or(|start| now >= start + self.bucket) {
+            self.expire(now);
+            self.buckets.push_back((now, 0, 0));
+        }
+        let bucket = self.buckets.back_mut().expect("a current bucket");
+        if hit {
+            bucket.1 += 1;
+        } else {
+            bucket.2 += 1;
+        }
+    }
+
+    /// Drops the buckets that started a whole window ago.
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(start, ..)) = self.buckets.front() {
+            if start + self.window > now {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+
+    /// The hits and misses still inside the window.
+    fn counts(&mut self, now: Instant) -> (u64, u64) {
+        self.expire(now);
+        self.buckets.iter().fold((0, 0), |(hits, misses), bucket| {
+            (hits + bucket.1, misses + bucket.2)
+        })
+    }
+}
+
+/// A value that differs from the stored one but has not yet proven stable.
+struct PendingChange {
+    value: u64,
+    seen: u32,
+    first_seen: Instant,
+}
+
+/// An update waiting for its key's throttle interval to pass.
+struct Throttled {
+    value: u64,
+    ttl: Option<Duration>,
+    source: Option<SourceId>,
+}
+
+/// What to do with a value outside the bounds set by `with_value_bounds`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnViolation {
+    /// Discard the value.
+    Drop,
+    /// Store the nearest bound instead.
+    Clamp,
+    /// Discard the value and report `CacheError::OutOfRange`.
+    Error,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ValueBounds {
+    min: u64,
+    max: u64,
+    on_violation: OnViolation,
+}
+
+/// See `StreamCacheBuilder::with_flap_suppression`.
+#[derive(Debug, Clone, Copy)]
+struct FlapSuppression {
+    window: Duration,
+    threshold: u32,
+}
+
+impl State {
+    fn tick(&mut self) -> u64 {
+        self.clock.fetch_add(1, AtomicOrdering::Relaxed) + 1
+    }
+
+    fn insert(&mut self, key: String, value: u64, meta: EntryMeta) -> Option<u64> {
+        let old = self.values.insert(key.clone(), value);
+        if old != Some(value) && self.log_applied {
+            self.applied.push(AppliedUpdate {
+                seq: self.seq.fetch_add(1, AtomicOrdering::Relaxed) + 1,
+                key: key.clone(),
+                old,
+                new: value,
+                source: meta.source.clone(),
+                timestamp: SystemTime::now(),
+            });
+        }
+        self.touch(key.clone(), meta);
+        if let Some(meta) = self.meta.get_mut(&key) {
+            meta.updates += 1;
+        }
+        if old != Some(value) {
+            self.metrics.updates += 1;
+            if let Some(aggregate) = &self.aggregate {
+                let mut aggregate = aggregate.lock().unwrap_or_else(|e| e.into_inner());
+                let aggregate = &mut *aggregate;
+                aggregate.value = (aggregate.fold)(&*aggregate.value, &key, old, value);
+            }
+            self.notify(ChangeEvent {
+                key,
+                old,
+                new: value,
+            });
+        }
+        old
+    }
+
+    /// Replaces the metadata of `key` without touching its value.
+    fn touch(&mut self, key: String, mut meta: EntryMeta) {
+        meta.last_used = self.tick();
+        meta.written = meta.last_used;
+        if let Some(old) = self.meta.get(&key) {
+            meta.updates = old.updates;
+        }
+        self.meta.insert(key, meta);
+    }
+
+    /// Reads `key`, dropping it first if its TTL has run out.
+    fn get_live(&mut self, key: &str, now: Instant) -> Option<u64> {
+        let tick = self.tick();
+        let meta = self.meta.get_mut(key)?;
+        if meta.is_expired(now) {
+            self.remove_expired_entry(key);
+            return None;
+        }
+        meta.last_used = tick;
+        self.values.get(key)
+    }
+
+    /// `get_live` for callers reading on behalf of the user, which counts
+    /// toward the hit/miss metrics and restarts the entry's TTL under sliding
+    /// expiry.
+    fn read(&mut self, key: &str, now: Instant) -> Option<u64> {
+        let Some(value) = self.get_live(key, now) else {
+            self.metrics.misses += 1;
+            self.hit_window.record(false, now);
+            return None;
+        };
+        self.metrics.hits += 1;
+        self.hit_window.record(true, now);
+        if self.sliding_ttl {
+            if let Some(meta) = self.meta.get_mut(key) {
+                if let Some(ttl) = meta.ttl {
+                    meta.expires_at = Some(now + ttl);
+                }
+            }
+        }
+        Some(value)
+    }
+
+    /// Reads an entry past its TTL but within the stale grace window.
+    fn read_stale(&mut self, key: &str, now: Instant) -> Option<u64> {
+        let meta = self.meta.get(key)?;
+        if !meta.is_expired(now) || meta.is_gone(now, self.stale_grace) {
+            return None;
+        }
+        self.metrics.hits += 1;
+        self.hit_window.record(true, now);
+        self.values.get(key)
+    }
+
+    /// Drops every entry, as for `Update::Resync`.
+    fn clear(&mut self) {
+        self.values.clear();
+        self.meta.clear();
+        self.flaps.clear();
+        self.throttled.clear();
+    }
+
+    /// Copies the unexpired entries.
+    fn live_snapshot(&self, now: Instant) -> HashMap<String, u64> {
+        self.values
+            .iter()
+            .filter(|(key, _)| !self.meta[*key].is_expired(now))
+            .map(|(key, value)| (key.clone(), value))
+            .collect()
+    }
+
+    /// Releases the memory held by the map beyond its current size.
+    fn shrink_to_fit(&mut self) {
+        self.values.shrink_to_fit();
+        self.meta.shrink_to_fit();
+        self.flaps.shrink_to_fit();
+        self.throttled.shrink_to_fit();
+    }
+
+    /// Drops every entry whose TTL (and stale grace) has run out.
+    fn sweep(&mut self, now: Instant) {
+        let expired: Vec<_> = self
+            .meta
+            .iter()
+            .filter(|(_, meta)| meta.is_gone(now, self.stale_grace))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove_expired_entry(&key);
+        }
+    }
+
+    fn remove_expired_entry(&mut self, key: &str) {
+        self.meta.remove(key);
+        self.flaps.remove(key);
+        if let Some(value) = self.values.remove(key) {
+            self.expired.push((key.to_string(), value));
+        }
+    }
+
+    /// Removes the least recently used entry that is not pinned, returning
+    /// its key.
+    fn evict_lru(&mut self) -> Option<String> {
+        let key = self
+            .meta
+            .iter()
+            .filter(|(key, _)| !self.pinned.contains(*key))
+            .min_by_key(|(_, meta)| meta.last_used)
+            .map(|(key, _)| key.clone())?;
+        self.values.remove(&key);
+        self.meta.remove(&key);
+        self.flaps.remove(&key);
+        Some(key)
+    }
+
+    fn notify(&mut self, event: ChangeEvent) {
+        if self.track_dirty && !self.dirty.contains(&event.key) {
+            self.dirty.insert(event.key.clone());
+        }
+        // Senders fail once their `Watch` is dropped, which unregisters them.
+        self.watchers
+            .retain(|watcher| watcher.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+#[derive(Clone)]
+struct Config {
+    poison_policy: PoisonPolicy,
+    conflict_policy: ConflictPolicy,
+    subscription_error_policy: SubscriptionErrorPolicy,
+    ttl: Option<Duration>,
+    refresh_ttl_on_equal: bool,
+    fetch_chunk_size: Option<usize>,
+    capacity: Option<usize>,
+    update_buffer: usize,
+    shards: usize,
+    skip_initial_fetch: bool,
+    on_expire: Option<ExpireCallback>,
+    sweep_interval: Option<Duration>,
+    json_log: Option<LogCallback>,
+    flap_suppression: Option<FlapSuppression>,
+    per_key_throttle: Option<ThrottleFn>,
+    on_error: Option<ErrorHandler>,
+    error_dedup_window: Option<Duration>,
+    value_bounds: Option<ValueBounds>,
+    sliding_ttl: bool,
+    measure_lock_contention: bool,
+    track_dirty: bool,
+    pins_count_toward_capacity: bool,
+    hit_ratio_window: Duration,
+    /// Retries of a failed initial fetch, and the first backoff.
+    initial_fetch_retries: (u32, Duration),
+    interest: Option<Vec<City>>,
+    snapshot_from_subscribe: bool,
+    idle_timeout: Option<Duration>,
+    empty_warn_after: Option<Duration>,
+    key_normalizer: Option<KeyNormalizer>,
+    fallback: Option<Arc<dyn Fallback>>,
+    promote_fallback: bool,
+    decay: Option<DecayFn>,
+    sentinel: Option<u64>,
+    stale_grace: Option<Duration>,
+    serialize_api_calls: bool,
+    middleware: Vec<Arc<dyn UpdateMiddleware>>,
+    write_priority: WritePriority,
+    #[cfg(feature = "opentelemetry")]
+    otel_context: Option<opentelemetry::Context>,
+    write_deadline: Option<Instant>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            poison_policy: PoisonPolicy::default(),
+            conflict_policy: ConflictPolicy::default(),
+            subscription_error_policy: SubscriptionErrorPolicy::default(),
+            ttl: None,
+            refresh_ttl_on_equal: true,
+            fetch_chunk_size: None,
+            capacity: None,
+            update_buffer: 1024,
+            shards: 1,
+            skip_initial_fetch: false,
+            on_expire: None,
+            sweep_interval: None,
+            json_log: None,
+            flap_suppression: None,
+            per_key_throttle: None,
+            on_error: None,
+            error_dedup_window: None,
+            value_bounds: None,
+            sliding_ttl: false,
+            measure_lock_contention: false,
+            track_dirty: false,
+            pins_count_toward_capacity: true,
+            hit_ratio_window: Duration::from_secs(60),
+            initial_fetch_retries: (0, Duration::ZERO),
+            interest: None,
+            snapshot_from_subscribe: false,
+            idle_timeout: None,
+            empty_warn_after: None,
+            key_normalizer: None,
+            decay: None,
+            sentinel: None,
+            stale_grace: None,
+            serialize_api_calls: false,
+            middleware: Vec::new(),
+            write_priority: WritePriority::default(),
+            #[cfg(feature = "opentelemetry")]
+            otel_context: None,
+            write_deadline: None,
+            fallback: None,
+            promote_fallback: false,
+        }
+    }
+}
+
+type Fetcher = Arc<dyn Fn() -> BoxFuture<'static, Result<Vec<Update>, String>> + Send + Sync>;
+
+/// A type-erased handle on an attached `Api`, kept for on-demand fetches.
+#[derive(Clone)]
+struct Source {
+    id: Option<SourceId>,
+    fetch: Fetcher,
+    status: Arc<Mutex<SourceStatus>>,
+    /// Stops this source's worker alone, for `StreamCache::set_api`. Closes
+    /// once the worker has exited.
+    stop: Arc<watch::Sender<bool>>,
+}
+
+/// The health of one attached `Api`, as reported by
+/// `StreamCache::source_status`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceStatus {
+    /// The `add_source` id, or `None` for `update_in_background`.
+    pub id: Option<SourceId>,
+    /// Whether a fetch from this source has been applied.
+    pub fetched: bool,
+    /// Whether its stream has produced at least one item.
+    pub streamed: bool,
+    /// The latest error from this source's fetch, stream or applier.
+    pub last_error: Option<CacheError>,
+}
+
+/// Updates a source's status; a poisoned status is still updated.
+fn record_status(status: &Mutex<SourceStatus>, f: impl FnOnce(&mut SourceStatus)) {
+    f(&mut status.lock().unwrap_or_else(|e| e.into_inner()));
+}
+
+/// One line of the JSON update log.
+struct AppliedUpdate {
+    seq: u64,
+    key: String,
+    old: Option<u64>,
+    new: u64,
+    source: Option<SourceId>,
+    timestamp: SystemTime,
+}
+
+impl AppliedUpdate {
+    fn to_json(&self) -> String {
+        let old = self.old.map_or("null".to_string(), |old| old.to_string());
+        let source = self
+            .source
+            .as_deref()
+            .map_or("null".to_string(), json_string);
+        let timestamp = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        format!(
+            r#"{{"seq":{},"key":{},"old":{},"new":{},"source":{},"timestamp":{}}}"#,
+            self.seq,
+            json_string(&self.key),
+            old,
+            self.new,
+            source,
+            timestamp
+        )
+    }
+}
+
+/// A small SplitMix64 generator for `StreamCache::sample`, seeded from the
+/// process's random hasher keys.
+struct SampleRng(u64);
+
+impl SampleRng {
+    fn new() -> Self {
+        SampleRng(RandomState::new().build_hasher().finish())
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A value in `0..bound`.
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+/// The message a caught panic was raised with.
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        return message.to_string();
+    }
+    match panic.downcast_ref::<String>() {
+        Some(message) => message.clone(),
+        None => "panic without a message".to_string(),
+    }
+}
+
+/// Quotes a CSV field if it holds a comma, quote or line break.
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if u32::from(c) < 0x20 => json.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Holds the cache lock and, once it is released, fires `on_expire` for the
+/// entries that expired meanwhile, emits the JSON update log and reports
+/// deferred worker errors, so callbacks may use the cache freely.
+struct StateGuard<'a> {
+    guard: Option<MutexGuard<'a, State>>,
+    inner: &'a Inner,
+}
+
+impl Deref for StateGuard<'_> {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        self.guard.as_ref().expect("guard is only taken on drop")
+    }
+}
+
+impl DerefMut for StateGuard<'_> {
+    fn deref_mut(&mut self) -> &mut State {
+        self.guard.as_mut().expect("guard is only taken on drop")
+    }
+}
+
+impl Drop for StateGuard<'_> {
+    fn drop(&mut self) {
+        let Some(mut guard) = self.guard.take() else {
+            return;
+        };
+        let expired = mem::take(&mut guard.expired);
+        let applied = mem::take(&mut guard.applied);
+        let errors = mem::take(&mut guard.errors);
+        let revalidate = mem::take(&mut guard.revalidate);
+        let empty = guard.values.is_empty();
+        drop(guard);
+        if revalidate {
+            self.inner.revalidate();
+        }
+        if empty {
+            self.inner.emptied.notify_waiters();
+        }
+        let config = &self.inner.config;
+        if let Some(on_expire) = &config.on_expire {
+            for (key, value) in expired {
+                on_expire(&key, value);
+            }
+        }
+        if let Some(json_log) = &config.json_log {
+            for update in applied {
+                json_log(&update.to_json());
+            }
+        }
+        for error in errors {
+            self.inner.report_error(error);
+        }
+    }
+}
+
+/// Every shard's lock, as `Inner::lock` takes it for operations on the
+/// whole cache.
+struct Shards<'a> {
+    guards: Vec<StateGuard<'a>>,
+    inner: &'a Inner,
+}
+
+impl<'a> Shards<'a> {
+    /// The shard holding `key`.
+    fn of(&mut self, key: &str) -> &mut State {
+        let shard = self.inner.shard_of(key);
+        &mut self.guards[shard]
+    }
+
+    /// The shard that keeps cache-wide bookkeeping.
+    fn first(&self) -> &State {
+        &self.guards[0]
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &State> {
+        self.guards.iter().map(|guard| &**guard)
+    }
+
+    fn iter_mut(&mut self) -> std::slice::IterMut<'_, StateGuard<'a>> {
+        self.guards.iter_mut()
+    }
+
+    /// Every unexpired entry, with its metadata.
+    fn live(&self, now: Instant) -> impl Iterator<Item = (&String, u64, &EntryMeta)> {
+        self.iter().flat_map(move |state| {
+            state.values.iter().filter_map(move |(key, value)| {
+                let meta = &state.meta[key];
+                (!meta.is_expired(now)).then_some((key, value, meta))
+            })
+        })
+    }
+
+    /// Copies the unexpired entries.
+    fn live_snapshot(&self, now: Instant) -> HashMap<String, u64> {
+        self.live(now)
+            .map(|(key, value, _)| (key.clone(), value))
+            .collect()
+    }
+
+    /// Drops every entry, as for `Update::Resync`.
+    fn clear(&mut self) {
+        for state in self.iter_mut() {
+            state.clear();
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.iter().all(|state| state.values.is_empty())
+    }
+}
+
+/// A running `stale_while_revalidate` refresh, which clears
+/// `Inner::revalidating` when dropped.
+struct Revalidating(Arc<Inner>);
+
+impl Drop for Revalidating {
+    fn drop(&mut self) {
+        self.0.revalidating.store(false, AtomicOrdering::SeqCst);
+    }
+}
+
+/// Repeats of `last` held back by `error_dedup_window`.
+struct ErrorDedup {
+    last: Option<CacheError>,
+    since: Instant,
+    suppressed: usize,
+}
+
+struct Inner {
+    /// Lets `&self` methods hand out owned references to background tasks.
+    this: Weak<Inner>,
+    /// The entries split by key hash, each shard under its own lock; see
+    /// `StreamCacheBuilder::shards`. The first shard also keeps cache-wide
+    /// bookkeeping such as the error history.
+    shards: Box<[Mutex<State>]>,
+    /// Picks the shard of a key.
+    shard_hasher: RandomState,
+    config: Config,
+    sources: Mutex<Vec<Source>>,
+    errors: Mutex<ErrorDedup>,
+    /// Set once by `shutdown_graceful`; workers stop reading their streams.
+    shutdown: watch::Sender<bool>,
+    /// Sources whose baseline is still loading; `wait_ready` waits for zero.
+    pending_baselines: watch::Sender<usize>,
+    workers: Mutex<Vec<JoinHandle<Result<(), CacheError>>>>,
+    running_workers: AtomicUsize,
+    /// Woken whenever the lock is released with no entries left.
+    emptied: Notify,
+    /// Set while a `stale_while_revalidate` refresh is running.
+    revalidating: AtomicBool,
+    /// Receives the final contents when `running_workers` drops to zero.
+    shutdown_snapshot: Mutex<Option<oneshot::Sender<HashMap<String, u64>>>>,
+    #[cfg(test)]
+    lock_count: std::sync::atomic::AtomicUsize,
+}
+
+impl Inner {
+    /// Takes every shard's lock, for operations on the whole cache.
+    fn lock(&self) -> Result<Shards<'_>, CacheError> {
+        #[cfg(test)]
+        self.lock_count
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        // Always in shard order, so two whole-cache locks cannot deadlock.
+        let guards = (0..self.shards.len())
+            .map(|shard| self.guard(shard))
+            .collect::<Result<_, _>>()?;
+        Ok(Shards {
+            guards,
+            inner: self,
+        })
+    }
+
+    /// Takes the lock of the shard holding `key`.
+    fn lock_key(&self, key: &str) -> Result<StateGuard<'_>, CacheError> {
+        self.lock_shard(self.shard_of(key))
+    }
+
+    fn lock_shard(&self, shard: usize) -> Result<StateGuard<'_>, CacheError> {
+        #[cfg(test)]
+        self.lock_count
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.guard(shard)
+    }
+
+    fn guard(&self, shard: usize) -> Result<StateGuard<'_>, CacheError> {
+        let guard = if self.config.measure_lock_contention {
+            self.lock_timed(&self.shards[shard])?
+        } else {
+            self.config.poison_policy.lock(&self.shards[shard])?
+        };
+        Ok(StateGuard {
+            guard: Some(guard),
+            inner: self,
+        })
+    }
+
+    /// `shard`'s share of the capacity. The first `capacity % shards`
+    /// shards take one entry more, so the shares add up to the capacity.
+    fn shard_capacity(&self, shard: usize) -> Option<usize> {
+        let shards = self.shards.len();
+        self.config
+            .capacity
+            .map(|capacity| capacity / shards + usize::from(shard < capacity % shards))
+    }
+
+    /// The shard `key` belongs to.
+    fn shard_of(&self, key: &str) -> usize {
+        match self.shards.len() {
+            1 => 0,
+            shards => (self.shard_hasher.hash_one(key) % shards as u64) as usize,
+        }
+    }
+
+    /// Takes `shard`'s lock, recording in the metrics how long it had to
+    /// wait if it was held elsewhere.
+    fn lock_timed<'a>(&self, shard: &'a Mutex<State>) -> Result<MutexGuard<'a, State>, CacheError> {
+        match shard.try_lock() {
+            Ok(guard) => return Ok(guard),
+            Err(TryLockError::Poisoned(_)) => return self.config.poison_policy.lock(shard),
+            Err(TryLockError::WouldBlock) => {}
+        }
+        let start = std::time::Instant::now();
+        let mut guard = self.config.poison_policy.lock(shard)?;
+        let waited = start.elapsed();
+        let metrics = &mut guard.metrics;
+        metrics.lock_wait_total += waited;
+        metrics.lock_wait_max = metrics.lock_wait_max.max(waited);
+        Ok(guard)
+    }
+
+    /// Passes a worker error to the error handler. With a dedup window, an
+    /// error identical to the previous one within the window is only
+    /// counted, and the count is reported in one call when the window ends.
+    fn report_error(&self, error: CacheError) {
+        {
+            // Bypasses the poison policy: bookkeeping must not panic or fail.
+            let mut state = self.shards[0].lock().unwrap_or_else(|e| e.into_inner());
+            state.metrics.errors += 1;
+            state.last_error = Some(error.clone());
+            if state.error_history.len() == ERROR_HISTORY {
+                state.error_history.pop_front();
+            }
+            state
+                .error_history
+                .push_back((Instant::now(), error.clone()));
+        }
+        let Some(on_error) = &self.config.on_error else {
+            return;
+        };
+        let Some(window) = self.config.error_dedup_window else {
+            on_error(&error, 1);
+            return;
+        };
+        let now = Instant::now();
+        let pending = {
+            let mut dedup = self.errors.lock().unwrap_or_else(|e| e.into_inner());
+            if dedup.last.as_ref() == Some(&error) && now - dedup.since < window {
+                dedup.suppressed += 1;
+                return;
+            }
+            let pending = dedup
+                .last
+                .replace(error.clone())
+                .filter(|_| dedup.suppressed > 0)
+                .map(|last| (last, dedup.suppressed));
+            dedup.since = now;
+            dedup.suppressed = 0;
+            pending
+        };
+        if let Some((last, repeats)) = pending {
+            on_error(&last, repeats);
+        }
+        on_error(&error, 1);
+
+        let inner = self.this.clone();
+        tokio::spawn(async move {
+            time::sleep(window).await;
+            if let Some(inner) = inner.upgrade() {
+                inner.flush_errors(now);
+            }
+        });
+    }
+
+    /// Reports the repeats counted in the dedup window that opened at `since`,
+    /// unless a different error already did.
+    fn flush_errors(&self, since: Instant) {
+        let pending = {
+            let mut dedup = self.errors.lock().unwrap_or_else(|e| e.into_inner());
+            if dedup.since != since || dedup.suppressed == 0 {
+                return;
+            }
+            let repeats = mem::take(&mut dedup.suppressed);
+            dedup.last.clone().map(|last| (last, repeats))
+        };
+        if let (Some(on_error), Some((last, repeats))) = (&self.config.on_error, pending) {
+            on_error(&last, repeats);
+        }
+    }
+
+    /// The canonical form of an incoming key.
+    fn normalize(&self, key: String) -> String {
+        match &self.config.key_normalizer {
+            Some(normalizer) => normalizer(&key),
+            None => key,
+        }
+    }
+
+    /// The canonical form of a key being looked up.
+    fn lookup_key<'k>(&self, key: &'k str) -> Cow<'k, str> {
+        match &self.config.key_normalizer {
+            Some(normalizer) => Cow::Owned(normalizer(key)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// `State::read` with the configured decay applied to the result. Under
+    /// `stale_while_revalidate` a stale entry is returned too, and a refresh
+    /// starts once the lock is released.
+    fn read(&self, state: &mut State, key: &str, now: Instant) -> Option<u64> {
+        let value = match state.read_stale(key, now) {
+            Some(value) => {
+                state.revalidate = true;
+                value
+            }
+            None => state.read(key, now)?,
+        };
+        Some(self.decay(state, key, value, now))
+    }
+
+    /// `read` without its side effects: no hit/miss counted, no LRU bump, no
+    /// TTL extension and no refresh of a stale entry.
+    fn peek(&self, state: &State, key: &str, now: Instant) -> Option<u64> {
+        if state.meta.get(key)?.is_gone(now, state.stale_grace) {
+            return None;
+        }
+        let value = state.values.get(key)?;
+        Some(self.decay(state, key, value, now))
+    }
+
+    /// `value`, as stored for `key`, with the configured decay applied.
+    fn decay(&self, state: &State, key: &str, value: u64, now: Instant) -> u64 {
+        match &self.config.decay {
+            Some(decay) => decay(value, now - state.meta[key].updated_at),
+            None => value,
+        }
+    }
+
+    /// Starts a span under `parent`, or under the `with_otel_context`
+    /// context if `None`. Without a context there is no span.
+    fn span(&self, name: &'static str, parent: Option<&Span>) -> Option<Span> {
+        #[cfg(feature = "opentelemetry")]
+        {
+            use opentelemetry::trace::{TraceContextExt, Tracer};
+            let parent = match parent {
+                Some(parent) => &parent.context,
+                None => self.config.otel_context.as_ref()?,
+            };
+            let span =
+                opentelemetry::global::tracer("streamed-cache").start_with_context(name, parent);
+            Some(Span {
+                context: parent.with_span(span),
+            })
+        }
+        #[cfg(not(feature = "opentelemetry"))]
+        {
+            let _ = (name, parent);
+            None
+        }
+    }
+
+    /// Fetches from every source in the background, unless a refresh is
+    /// already running.
+    /// Skipped outside a Tokio runtime; the next stale read there tries
+    /// again.
+    fn revalidate(&self) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        if self.revalidating.swap(true, AtomicOrdering::SeqCst) {
+            return;
+        }
+        let Some(inner) = self.this.upgrade() else {
+            self.revalidating.store(false, AtomicOrdering::SeqCst);
+            return;
+        };
+        runtime.spawn(async move {
+            // Clears `revalidating` however the refresh ends, panics included.
+            let refresh = Revalidating(inner);
+            let inner = &refresh.0;
+            let sources = match inner.config.poison_policy.lock(&inner.sources) {
+                Ok(sources) => sources.clone(),
+                Err(_) => Vec::new(),
+            };
+            for source in sources {
+                let result = match (source.fetch)().await {
+                    Ok(fetched) => inner
+                        .apply_fetch(fetched, source.id.as_ref())
+                        .await
+                        .map(drop),
+                    Err(err) => Err(CacheError::Fetch(err)),
+                };
+                if let Err(err) = result {
+                    inner.report_error(err);
+                }
+            }
+        });
+    }
+
+    /// `ttl` overrides the cache-wide TTL for this entry.
+    fn entry_meta(
+        &self,
+        source: Option<&SourceId>,
+        ttl: Option<Duration>,
+        now: Instant,
+    ) -> EntryMeta {
+        let ttl = ttl.or(self.config.ttl);
+        EntryMeta {
+            source: source.cloned(),
+            origin: Origin::Stream,
+            expires_at: ttl.map(|ttl| now + ttl),
+            ttl,
+            updated_at: now,
+            last_used: 0,
+            written: 0,
+            updates: 0,
+        }
+    }
+
+    /// Collapses repeated keys within one fetch result with the conflict
+    /// policy, treating later items as newer, so duplicates are resolved
+    /// among themselves before meeting the cache. First-seen order is kept.
+    fn merge_fetched(&self, fetched: Vec<Update>) -> Vec<(City, Temperature, Option<Duration>)> {
+        let mut merged: Vec<(City, Temperature, Option<Duration>)> = Vec::new();
+        let mut positions: HashMap<City, usize> = HashMap::new();
+        let items = fetched.into_iter().flat_map(Update::into_entries);
+        for (city, temperature, ttl) in items {
+            let city = self.normalize(city);
+            let Some(&position) = positions.get(&city) else {
+                positions.insert(city.clone(), merged.len());
+                merged.push((city, temperature, ttl));
+                continue;
+            };
+            let entry = &mut merged[position];
+            let value =
+                self.config
+                    .conflict_policy
+                    .resolve(&city, entry.1, temperature, Origin::Stream);
+            if value != entry.1 || value == temperature {
+                entry.1 = value;
+                entry.2 = ttl;
+            }
+        }
+        merged
+    }
+
+    /// Applies a fetch result, in chunks if `fetch_chunk_size` is set, and
+    /// returns the entries it inserted or changed.
+    async fn apply_fetch(
+        &self,
+        fetched: Vec<Update>,
+        source: Option<&SourceId>,
+    ) -> Result<Vec<ChangeEvent>, CacheError> {
+        let fetched = self.merge_fetched(fetched);
+        let chunk_size = self.config.fetch_chunk_size.unwrap_or(fetched.len().max(1));
+        let mut changes = Vec::new();
+        let mut fetched = fetched.into_iter().peekable();
+        while fetched.peek().is_some() {
+            {
+                let mut shards = self.lock()?;
+                for (city, temperature, ttl) in fetched.by_ref().take(chunk_size) {
+                    let state = shards.of(&city);
+                    let old = match self.apply(
+                        state,
+                        city.clone(),
+                        temperature,
+                        ttl,
+                        source,
+                        Origin::Fetch,
+                    ) {
+                        Ok(InsertOutcome::Inserted | InsertOutcome::Evicted { .. }) => None,
+                        Ok(InsertOutcome::Updated { old }) => Some(old),
+                        Ok(
+                            InsertOutcome::RejectedByPolicy { .. }
+                            | InsertOutcome::Dropped
+                            | InsertOutcome::Deferred,
+                        ) => continue,
+                        Err(err) => {
+                            state.errors.push(err);
+                            continue;
+                        }
+                    };
+                    let new = state.values.get(&city).expect("applied entry has a value");
+                    if old != Some(new) {
+                        changes.push(ChangeEvent {
+                            key: city,
+                            old,
+                            new,
+                        });
+                    }
+                }
+            }
+            tokio::task::yield_now().await;
+        }
+        Ok(changes)
+    }
+
+    fn apply_update(
+        &self,
+        shards: &mut Shards,
+        update: Update,
+        source: Option<&SourceId>,
+        origin: Origin,
+    ) {
+        match update {
+            // Frozen data is not cleared either.
+            Update::Resync if self.past_deadline() => {}
+            Update::Resync => shards.clear(),
+            Update::SnapshotComplete => {}
+            update => {
+                for (city, temperature, ttl) in update.into_entries() {
+                    let city = self.normalize(city);
+                    let state = shards.of(&city);
+                    self.apply_deferring_errors(state, city, temperature, ttl, source, origin);
+                }
+            }
+        }
+    }
+
+    /// Applies the entries of `update`, all of which belong to `state`'s
+    /// shard.
+    fn apply_to_shard(
+        &self,
+        state: &mut State,
+        update: Update,
+        source: Option<&SourceId>,
+        origin: Origin,
+    ) {
+        for (city, temperature, ttl) in update.into_entries() {
+            let city = self.normalize(city);
+            self.apply_deferring_errors(state, city, temperature, ttl, source, origin);
+        }
+    }
+
+    /// Splits the entries of `update` by shard, keeping their order within
+    /// each shard.
+    fn split_by_shard(&self, update: Update) -> Vec<(usize, Update)> {
+        match update {
+            Update::Batch(batch) => {
+                let mut split: Vec<Vec<(City, Temperature)>> = vec![Vec::new(); self.shards.len()];
+                for (city, temperature) in batch {
+                    split[self.shard_of(&self.lookup_key(&city))].push((city, temperature));
+                }
+                split
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, batch)| !batch.is_empty())
+                    .map(|(shard, batch)| (shard, Update::Batch(batch)))
+                    .collect()
+            }
+            Update::Single(ref city, _) | Update::Expiring(ref city, ..) => {
+                vec![(self.shard_of(&self.lookup_key(city)), update)]
+            }
+            Update::Resync | Update::SnapshotComplete => Vec::new(),
+        }
+    }
+
+    /// Applies a streamed update under `ConflictPolicy::Async`, awaiting the
+    /// resolver without the lock held. If the entry changes meanwhile, the
+    /// resolver runs again against the new value.
+    async fn apply_resolving(
+        &self,
+        resolver: &AsyncResolver,
+        update: Update,
+        source: Option<&SourceId>,
+    ) -> Result<(), CacheError> {
+        for (city, incoming, ttl) in update.into_entries() {
+            if self.config.sentinel == Some(incoming) {
+                continue;
+            }
+            let city = self.normalize(city);
+            let mut existing = self.lock_key(&city)?.get_live(&city, Instant::now());
+            loop {
+                let value = match existing {
+                    Some(existing) => resolver(&city, existing, incoming).await,
+                    None => incoming,
+                };
+                let mut state = self.lock_key(&city)?;
+                let current = state.get_live(&city, Instant::now());
+                if current != existing {
+                    existing = current;
+                    continue;
+                }
+                if let Err(err) = self.apply(&mut state, city, value, ttl, source, Origin::Stream) {
+                    state.errors.push(err);
+                }
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// The resolver streamed updates await under `ConflictPolicy::Async`.
+    /// Snapshot streams keep the synchronous path, which falls back to
+    /// last-write-wins.
+    fn stream_resolver(&self) -> Option<AsyncResolver> {
+        match &self.config.conflict_policy {
+            ConflictPolicy::Async(resolver) if !self.config.snapshot_from_subscribe => {
+                Some(Arc::clone(resolver))
+            }
+            _ => None,
+        }
+    }
+
+    /// Takes a streamed update through the steps that run before the lock:
+    /// the middleware chain, then `resolver`, which applies the entries
+    /// itself. Shared by the background applier and `Driver`.
+    async fn prepare_streamed(
+        &self,
+        update: Update,
+        source: Option<&SourceId>,
+        resolver: Option<&AsyncResolver>,
+        parent: Option<&Span>,
+    ) -> Result<Prepared, CacheError> {
+        let update = if self.config.middleware.is_empty() {
+            update
+        } else {
+            match self.run_middleware(update, source).await {
+                Some(update) => update,
+                None => return Ok(Prepared::Vetoed),
+            }
+        };
+        match resolver {
+            Some(resolver)
+                if matches!(
+                    update,
+                    Update::Single(..) | Update::Expiring(..) | Update::Batch(_)
+                ) =>
+            {
+                let _span = self.span("apply", parent);
+                self.apply_resolving(resolver, update, source).await?;
+                Ok(Prepared::Applied)
+            }
+            _ => Ok(Prepared::Pending(update)),
+        }
+    }
+
+    /// Passes the entries of a streamed update through the middleware chain,
+    /// returning what is left of it.
+    async fn run_middleware(&self, update: Update, source: Option<&SourceId>) -> Option<Update> {
+        match update {
+            Update::Single(key, value) => {
+                let (key, value) = self.through_chain(key, value, source).await?;
+                Some(Update::Single(key, value))
+            }
+            Update::Expiring(key, value, ttl) => {
+                let (key, value) = self.through_chain(key, value, source).await?;
+                Some(Update::Expiring(key, value, ttl))
+            }
+            Update::Batch(batch) => {
+                let mut kept = Vec::with_capacity(batch.len());
+                for (key, value) in batch {
+                    kept.extend(self.through_chain(key, value, source).await);
+                }
+                (!kept.is_empty()).then_some(Update::Batch(kept))
+            }
+            update @ (Update::Resync | Update::SnapshotComplete) => Some(update),
+        }
+    }
+
+    async fn through_chain(
+        &self,
+        key: String,
+        value: u64,
+        source: Option<&SourceId>,
+    ) -> Option<(String, u64)> {
+        let mut ctx = UpdateContext {
+            key,
+            value,
+            source: source.cloned(),
+        };
+        for middleware in &self.config.middleware {
+            if middleware.on_update(&mut ctx).await.is_break() {
+                return None;
+            }
+        }
+        Some((ctx.key, ctx.value))
+    }
+
+    /// Opens `api`'s stream, narrowed to the interest set if one is configured.
+    /// A panic while opening it becomes a stream holding only a
+    /// `CacheError::SubscribePanic`.
+    async fn subscribe<'a>(&self, api: &'a impl Api) -> BoxStream<'a, Result<Update, CacheError>> {
+        let opened = panic::AssertUnwindSafe(async {
+            match &self.config.interest {
+                Some(keys) => api
+                    .subscribe_keys(keys)
+                    .await
+                    .map(|item| item.map(|(city, temperature)| Update::Single(city, temperature)))
+                    .boxed(),
+                None => api.subscribe_updates().await,
+            }
+        })
+        .catch_unwind()
+        .await;
+        match opened {
+            Ok(stream) => stream
+                .map(|item| item.map_err(CacheError::Subscribe))
+                .boxed(),
+            Err(panic) => {
+                let err = CacheError::SubscribePanic(panic_message(&*panic));
+                futures::stream::iter([Err(err)]).boxed()
+            }
+        }
+    }
+
+    /// Called as a worker exits; the last one hands off the final contents.
+    fn worker_exited(&self) {
+        if self.running_workers.fetch_sub(1, AtomicOrdering::SeqCst) != 1 {
+            return;
+        }
+        let sender = self
+            .shutdown_snapshot
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        if let Some(sender) = sender {
+            if let Ok(state) = self.lock() {
+                let _ = sender.send(state.live_snapshot(Instant::now()));
+            }
+        }
+    }
+
+    /// Marks one source's baseline (initial fetch or stream snapshot) as in.
+    fn baseline_done(&self) {
+        self.pending_baselines.send_modify(|pending| *pending -= 1);
+    }
+
+    /// Fetches from `api` and applies the result, reporting any error and
+    /// recording the outcome in `status`. Returns whether it succeeded.
+    async fn fetch_from(
+        &self,
+        api: &impl Api,
+        source: Option<&SourceId>,
+        status: &Mutex<SourceStatus>,
+    ) -> bool {
+        let _span = self.span("fetch", None);
+        let result = match api.fetch_updates().await {
+            Ok(fetched) => self.apply_fetch(fetched, source).await.map(drop),
+            Err(err) => Err(CacheError::Fetch(err)),
+        };
+        match result {
+            Ok(()) => {
+                record_status(status, |status| status.fetched = true);
+                true
+            }
+            Err(err) => {
+                record_status(status, |status| status.last_error = Some(err.clone()));
+                self.report_error(err);
+                false
+            }
+        }
+    }
+
+    /// `apply` for the worker, whose errors go to the error handler once the
+    /// lock is released. `key` is already normalized.
+    fn apply_deferring_errors(
+        &self,
+        state: &mut State,
+        key: String,
+        incoming: u64,
+        ttl: Option<Duration>,
+        source: Option<&SourceId>,
+        origin: Origin,
+    ) {
+        if let Err(err) = self.apply(state, key, incoming, ttl, source, origin) {
+            state.errors.push(err);
+        }
+    }
+
+    /// Whether the `with_write_deadline` cutoff has passed.
+    fn past_deadline(&self) -> bool {
+        self.config
+            .write_deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Checks `value` against the configured bounds, returning what should
+    /// be stored, or `None` if it should be dropped.
+    fn bound(&self, key: &str, value: u64) -> Result<Option<u64>, CacheError> {
+        if self.config.sentinel == Some(value) {
+            return Ok(None);
+        }
+        let Some(bounds) = self.config.value_bounds else {
+            return Ok(Some(value));
+        };
+        if (bounds.min..=bounds.max).contains(&value) {
+            return Ok(Some(value));
+        }
+        match bounds.on_violation {
+            OnViolation::Drop => Ok(None),
+            OnViolation::Clamp => Ok(Some(value.clamp(bounds.min, bounds.max))),
+            OnViolation::Error => Err(CacheError::OutOfRange {
+                key: key.to_string(),
+                value,
+            }),
+        }
+    }
+
+    /// Whether flap suppression holds back `incoming` in place of `existing`.
+    fn is_flapping(
+        &self,
+        state: &mut State,
+        key: &str,
+        existing: u64,
+        incoming: u64,
+        now: Instant,
+    ) -> bool {
+        let Some(flap) = self.config.flap_suppression else {
+            return false;
+        };
+        if incoming == existing {
+            state.flaps.remove(key);
+            return false;
+        }
+        let pending = state
+            .flaps
+            .entry(key.to_string())
+            .and_modify(|pending| {
+                if pending.value == incoming {
+                    pending.seen += 1;
+                } else {
+                    *pending = PendingChange {
+                        value: incoming,
+                        seen: 1,
+                        first_seen: now,
+                    };
+                }
+            })
+            .or_insert(PendingChange {
+                value: incoming,
+                seen: 1,
+                first_seen: now,
+            });
+        if pending.seen >= flap.threshold || now - pending.first_seen >= flap.window {
+            state.flaps.remove(key);
+            return false;
+        }
+        true
+    }
+
+    /// Whether the per-key throttle holds back `incoming` because the key was
+    /// updated less than its interval ago. Only the latest held update is
+    /// kept; a timer applies it when the interval ends. Outside a Tokio
+    /// runtime there is no timer, so nothing is held.
+    fn is_throttled(
+        &self,
+        state: &mut State,
+        key: &str,
+        incoming: u64,
+        ttl: Option<Duration>,
+        source: Option<&SourceId>,
+        now: Instant,
+    ) -> bool {
+        let Some(throttle) = &self.config.per_key_throttle else {
+            return false;
+        };
+        let Some(interval) = throttle(key) else {
+            return false;
+        };
+        let Some(meta) = state.meta.get(key) else {
+            return false;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return false;
+        };
+        let due = meta.updated_at + interval;
+        if now >= due {
+            state.throttled.remove(key);
+            return false;
+        }
+        let held = Throttled {
+            value: incoming,
+            ttl,
+            source: source.cloned(),
+        };
+        if state.throttled.insert(key.to_string(), held).is_none() {
+            let inner = self.this.clone();
+            let key = key.to_string();
+            runtime.spawn(async move {
+                time::sleep_until(due).await;
+                if let Some(inner) = inner.upgrade() {
+                    inner.flush_throttled(key);
+                }
+            });
+        }
+        true
+    }
+
+    /// Applies the update the throttle held back for `key`, if any is left.
+    fn flush_throttled(&self, key: String) {
+        let mut state = match self.lock_key(&key) {
+            Ok(state) => state,
+            Err(err) => return self.report_error(err),
+        };
+        let Some(held) = state.throttled.remove(&key) else {
+            return;
+        };
+        if let Err(err) = self.apply(
+            &mut state,
+            key,
+            held.value,
+            held.ttl,
+            held.source.as_ref(),
+            Origin::Stream,
+        ) {
+            state.errors.push(err);
+        }
+    }
+
+    /// Evicts until `state`, the shard of `key`, has room for one more key
+    /// under the capacity, returning the last key evicted.
+    fn make_room(&self, state: &mut State, key: &str) -> Option<String> {
+        let capacity = self.shard_capacity(self.shard_of(key))?;
+        let exempt = if self.config.pins_count_toward_capacity {
+            0
+        } else {
+            let state = &*state;
+            state
+                .pinned
+                .iter()
+                .filter(|key| state.values.contains_key(key))
+                .count()
+        };
+        let mut evicted = None;
+        while state.values.len() - exempt >= capacity {
+            let Some(key) = state.evict_lru() else {
+                break;
+            };
+            evicted = Some(key);
+        }
+        evicted
+    }
+
+    /// Merges an incoming value into `state` using the conflict policy. The
+    /// source is only recorded when the incoming value actually wins.
+    fn apply(
+        &self,
+        state: &mut State,
+        key: String,
+        incoming: u64,
+        ttl: Option<Duration>,
+        source: Option<&SourceId>,
+        origin: Origin,
+    ) -> Result<InsertOutcome, CacheError> {
+        if self.past_deadline() {
+            return Err(CacheError::PastDeadline {
+                key,
+                value: incoming,
+            });
+        }
+        let Some(incoming) = self.bound(&key, incoming)? else {
+            return Ok(InsertOutcome::Dropped);
+        };
+        let now = Instant::now();
+        if origin == Origin::Stream && self.is_throttled(state, &key, incoming, ttl, source, now) {
+            return Ok(InsertOutcome::Deferred);
+        }
+        let Some(existing) = state.get_live(&key, now) else {
+            let evicted = self.make_room(state, &key);
+            state.insert(
+                key,
+                incoming,
+                EntryMeta {
+                    origin,
+                    ..self.entry_meta(source, ttl, now)
+                },
+            );
+            return Ok(match evicted {
+                Some(key) => InsertOutcome::Evicted { key },
+                None => InsertOutcome::Inserted,
+            });
+        };
+        if origin == Origin::Fetch
+            && self.config.write_priority == WritePriority::SubscribeFirst
+            && state.meta[&key].origin == Origin::Stream
+        {
+            return Ok(InsertOutcome::RejectedByPolicy { kept: existing });
+        }
+        if self.is_flapping(state, &key, existing, incoming, now) {
+            return Ok(InsertOutcome::RejectedByPolicy { kept: existing });
+        }
+        let value = self
+            .config
+            .conflict_policy
+            .resolve(&key, existing, incoming, origin);
+        if value == existing {
+            if value != incoming {
+                return Ok(InsertOutcome::RejectedByPolicy { kept: existing });
+            }
+            // An identical value leaves the map alone, but may keep the
+            // entry alive.
+            if self.config.refresh_ttl_on_equal {
+                state.touch(
+                    key,
+                    EntryMeta {
+                        origin,
+                        ..self.entry_meta(source, ttl, now)
+                    },
+                );
+            }
+            return Ok(InsertOutcome::Updated { old: existing });
+        }
+        state.insert(
+            key,
+            value,
+            EntryMeta {
+                origin,
+                ..self.entry_meta(source, ttl, now)
+            },
+        );
+        Ok(InsertOutcome::Updated { old: existing })
+    }
+}
+
+#[derive(Default)]
+pub struct StreamCacheBuilder {
+    config: Config,
+    initial: HashMap<String, u64>,
+    shutdown_snapshot: Option<oneshot::Sender<HashMap<String, u64>>>,
+    store: Box<dyn Store>,
+    aggregate: Option<Aggregate>,
+}
+
+impl StreamCacheBuilder {
+    pub fn poison_policy(mut self, policy: PoisonPolicy) -> Self {
+        self.config.poison_policy = policy;
+        self
+    }
+
+    pub fn conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.config.conflict_policy = policy;
+        self
+    }
+
+    pub fn subscription_error_policy(mut self, policy: SubscriptionErrorPolicy) -> Self {
+        self.config.subscription_error_policy = policy;
+        self
+    }
+
+    /// The span `StreamCache::windowed_hit_ratio` looks back over, one
+    /// minute by default.
+    pub fn hit_ratio_window(mut self, window: Duration) -> Self {
+        self.config.hit_ratio_window = window;
+        self
+    }
+
+    /// Records in `metrics()` how long lock acquisitions wait when the lock
+    /// is held elsewhere. Uncontended acquisitions only pay for a `try_lock`.
+    pub fn measure_lock_contention(mut self, enabled: bool) -> Self {
+        self.config.measure_lock_contention = enabled;
+        self
+    }
+
+    /// Records which keys change, for `StreamCache::take_dirty`. Off by
+    /// default, since a cache that never drains the set would keep a copy of
+    /// every key it has seen change.
+    pub fn track_dirty(mut self, enabled: bool) -> Self {
+        self.config.track_dirty = enabled;
+        self
+    }
+
+    /// Retries a failed initial fetch up to `retries` times, waiting
+    /// `backoff` before the first retry and doubling it each time, up to 30
+    /// seconds. After that the cache relies on the stream. Every failure is
+    /// reported. A reconnecting subscription backs off from the same delay.
+    pub fn initial_fetch_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.config.initial_fetch_retries = (retries, backoff);
+        self
+    }
+
+    /// Subscribes through `Api::subscribe_keys` with `keys`, so an upstream
+    /// that supports it only streams those keys.
+    pub fn interest(mut self, keys: impl IntoIterator<Item = impl Into<City>>) -> Self {
+        self.config.interest = Some(keys.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Decides between a worker's initial fetch and its first streamed
+    /// updates, which otherwise race for the lock. This decides the outcome
+    /// whenever the conflict policy depends on arrival order, e.g. a
+    /// `Custom` resolver that keeps the incoming value.
+    pub fn write_priority(mut self, priority: WritePriority) -> Self {
+        self.config.write_priority = priority;
+        self
+    }
+
+    /// Runs every streamed entry through `chain`, in order, before it is
+    /// stored; fetched snapshots skip it. Each update is awaited through the
+    /// chain without the lock held, so a slow middleware throttles its
+    /// stream and queued updates are no longer applied in batches.
+    pub fn with_middleware(mut self, chain: Vec<Arc<dyn UpdateMiddleware>>) -> Self {
+        self.config.middleware = chain;
+        self
+    }
+
+    /// Joins the OpenTelemetry trace `context`: each worker fetch, each
+    /// subscription and each applied update becomes a span of the global
+    /// tracer provider. Fetch and subscribe spans are children of
+    /// `context`, and update spans children of their subscription.
+    #[cfg(feature = "opentelemetry")]
+    pub fn with_otel_context(mut self, context: opentelemetry::Context) -> Self {
+        self.config.otel_context = Some(context);
+        self
+    }
+
+    /// For clients that are not safe for concurrent use: guards every call
+    /// into a source's `Api` with one lock, so a fetch never overlaps
+    /// another fetch or opening the subscription. Reading an open stream is
+    /// not guarded, so a quiet stream never holds up a fetch.
+    pub fn serialize_api_calls(mut self, serialize: bool) -> Self {
+        self.config.serialize_api_calls = serialize;
+        self
+    }
+
+    /// For streams that open with the full current state: skips `fetch` and
+    /// applies everything up to `Update::SnapshotComplete` as a fetch would
+    /// (so it never overwrites streamed values), the rest as deltas.
+    pub fn snapshot_from_subscribe(mut self, enabled: bool) -> Self {
+        self.config.snapshot_from_subscribe = enabled;
+        self
+    }
+
+    /// Treats a subscription that sends nothing for `idle` as stalled: the
+    /// worker reports `CacheError::Idle` and applies the subscription error
+    /// policy, so `Reconnect` resubscribes.
+    pub fn idle_timeout(mut self, idle: Duration) -> Self {
+        self.config.idle_timeout = Some(idle);
+        self
+    }
+
+    /// Entries expire `ttl` after they were last written; `get` treats an
+    /// expired entry as missing.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.config.ttl = Some(ttl);
+        self
+    }
+
+    /// Reports `CacheError::NoData` to the error handler if the cache is
+    /// still empty `grace` after it was built, which usually means a dead
+    /// upstream or a misconfiguration.
+    pub fn empty_warn_after(mut self, grace: Duration) -> Self {
+        self.config.empty_warn_after = Some(grace);
+        self
+    }
+
+    /// Canonicalizes every key on the way in and out, e.g. to collapse
+    /// casing, so `get("PARIS")` finds an entry streamed as "paris". The
+    /// normalizer should be idempotent.
+    pub fn with_key_normalizer(mut self, normalizer: KeyNormalizer) -> Self {
+        self.config.key_normalizer = Some(normalizer);
+        self
+    }
+
+    /// Keeps the values in `store` instead of a `HashMap`, e.g. a
+    /// `BTreeStore` to allow `range` queries. Anything already in `store` is
+    /// discarded; seed the cache with `initial` instead.
+    pub fn store(mut self, store: impl Store + 'static) -> Self {
+        self.store = Box::new(store);
+        self
+    }
+
+    /// For availability over freshness: for `grace` past its TTL, `get`
+    /// still returns an entry's value, and each such stale read starts a
+    /// background fetch from every source to refresh it. `get_with_age`
+    /// flags the value as stale with an age beyond the TTL.
+    pub fn stale_while_revalidate(mut self, grace: Duration) -> Self {
+        self.config.stale_grace = Some(grace);
+        self
+    }
+
+    /// Maintains a running aggregate, e.g. a sum for a global average:
+    /// every write that changes a value is folded into it with `fold`, and
+    /// `StreamCache::aggregate` reads it. Removals, expiry and eviction are
+    /// not folded.
+    pub fn with_aggregate<A: Send + 'static>(mut self, init: A, fold: AggregateFn<A>) -> Self {
+        self.aggregate = Some(Aggregate {
+            value: Box::new(init),
+            fold: Box::new(move |aggregate, key, old, new| {
+                let aggregate = aggregate
+                    .downcast_ref()
+                    .expect("aggregate has its own type");
+                Box::new(fold(aggregate, key, old, new))
+            }),
+        });
+        self
+    }
+
+    /// Treats `value` as "unknown": updates carrying it are dropped, so they
+    /// neither overwrite a real value nor create an entry `get` would return.
+    pub fn sentinel(mut self, value: u64) -> Self {
+        self.config.sentinel = Some(value);
+        self
+    }
+
+    /// Ages values on the way out: `get`, `get_as` and `get_with_age` return
+    /// `decay(value, age)`, where `age` is the time since the entry was last
+    /// updated. The stored value is left alone, so a refresh resets the
+    /// decay.
+    pub fn with_decay(mut self, decay: DecayFn) -> Self {
+        self.config.decay = Some(decay);
+        self
+    }
+
+    /// Like `ttl`, but every successful read also restarts the entry's TTL,
+    /// so keys that keep being read never expire while idle ones do.
+    /// Per-update TTLs slide the same way.
+    pub fn sliding_ttl(mut self, ttl: Duration) -> Self {
+        self.config.ttl = Some(ttl);
+        self.config.sliding_ttl = true;
+        self
+    }
+
+    /// Whether an update carrying the value already stored restarts the
+    /// entry's TTL (the default). When false, repeated identical values do
+    /// not keep a stale entry alive.
+    pub fn refresh_ttl_on_equal(mut self, refresh: bool) -> Self {
+        self.config.refresh_ttl_on_equal = refresh;
+        self
+    }
+
+    /// Holds at most `capacity` entries, evicting the least recently used
+    /// one to make room for a new key.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.config.capacity = Some(capacity.max(1));
+        self
+    }
+
+    /// Whether entries pinned with `StreamCache::pin` take up `capacity`
+    /// slots. They do by default; either way they are never evicted, so a
+    /// cache whose entries are all pinned grows past its capacity.
+    pub fn pins_count_toward_capacity(mut self, count: bool) -> Self {
+        self.config.pins_count_toward_capacity = count;
+        self
+    }
+
+    /// Called for each entry removed because its TTL ran out, either by the
+    /// `get` that discovers it or by the sweeper. It runs after the cache
+    /// lock is released.
+    pub fn on_expire(mut self, on_expire: ExpireCallback) -> Self {
+        self.config.on_expire = Some(on_expire);
+        self
+    }
+
+    /// Freezes the cache at `deadline`: every value arriving from then on,
+    /// streamed, fetched or inserted, is dropped and reported as
+    /// `CacheError::PastDeadline`, like an out-of-range value. The workers
+    /// keep draining their streams and reads keep working.
+    pub fn with_write_deadline(mut self, deadline: Instant) -> Self {
+        self.config.write_deadline = Some(deadline);
+        self
+    }
+
+    /// Checks every incoming value against `min..=max` before storing it.
+    /// Violations are dropped, clamped, or dropped and reported as
+    /// `CacheError::OutOfRange` (to the error handler for worker updates, or
+    /// returned by `insert`).
+    pub fn with_value_bounds(mut self, min: u64, max: u64, on_violation: OnViolation) -> Self {
+        self.config.value_bounds = Some(ValueBounds {
+            min,
+            max,
+            on_violation,
+        });
+        self
+    }
+
+    /// Consults `fallback` when `get_or_fallback` misses; with `promote`, the
+    /// value found there is stored locally as if fetched.
+    pub fn fallback(mut self, fallback: impl Fallback, promote: bool) -> Self {
+        self.config.fallback = Some(Arc::new(fallback));
+        self.config.promote_fallback = promote;
+        self
+    }
+
+    /// Holds an existing value until a different one has arrived `threshold`
+    /// times in a row, or keeps arriving for at least `window`. A sensor
+    /// alternating between two readings then no longer churns the cache.
+    pub fn with_flap_suppression(mut self, window: Duration, threshold: u32) -> Self {
+        self.config.flap_suppression = Some(FlapSuppression { window, threshold });
+        self
+    }
+
+    /// Gives each key an optional minimum interval between applied streamed
+    /// updates. Updates arriving sooner are coalesced, and the latest is
+    /// applied once the interval has passed; keys mapped to `None` are not
+    /// throttled.
+    pub fn with_per_key_throttle(mut self, throttle: ThrottleFn) -> Self {
+        self.config.per_key_throttle = Some(throttle);
+        self
+    }
+
+    /// Called with each error the background worker hits (failed fetches,
+    /// stream errors, a poisoned lock).
+    pub fn on_error(mut self, on_error: ErrorHandler) -> Self {
+        self.config.on_error = Some(on_error);
+        self
+    }
+
+    /// Collapses identical errors within `window` into a single extra call
+    /// to the error handler carrying the repeat count, so an outage does not
+    /// flood the logs. The first error of a window is reported immediately.
+    pub fn error_dedup_window(mut self, window: Duration) -> Self {
+        self.config.error_dedup_window = Some(window);
+        self
+    }
+
+    /// Emits every applied update as a JSON object with `seq`, `key`, `old`,
+    /// `new`, `source` and `timestamp` (milliseconds since the Unix epoch).
+    /// It runs after the cache lock is released.
+    pub fn json_log(mut self, log: LogCallback) -> Self {
+        self.config.json_log = Some(log);
+        self
+    }
+
+    /// Like `json_log`, but writes newline-delimited JSON to `writer`. Write
+    /// errors are ignored so a broken sink cannot stall the cache.
+    pub fn json_log_writer(self, writer: impl Write + Send + 'static) -> Self {
+        let writer = Mutex::new(writer);
+        self.json_log(Arc::new(move |line: &str| {
+            if let Ok(mut writer) = writer.lock() {
+                let _ = writeln!(writer, "{line}");
+            }
+        }))
+    }
+
+    /// Removes expired entries every `interval` in the background, instead
+    /// of only when they are next read or written.
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.config.sweep_interval = Some(interval);
+        self
+    }
+
+    /// Starts workers with only the subscription loop; `fetch` then runs
+    /// only when asked for through `fetch_now`.
+    pub fn skip_initial_fetch(mut self, skip: bool) -> Self {
+        self.config.skip_initial_fetch = skip;
+        self
+    }
+
+    /// How many stream updates may queue up waiting to be applied before the
+    /// subscription stops being polled (default 1024).
+    pub fn update_buffer(mut self, size: usize) -> Self {
+        self.config.update_buffer = size.max(1);
+        self
+    }
+
+    /// Splits the map by key hash into `shards` parts (default 1), each under
+    /// its own lock and fed by its own applier task in every worker, so
+    /// updates to different keys apply in parallel on a multi-threaded
+    /// runtime. Updates to the same key stay in order, but an
+    /// `Update::Batch` is split by shard and no longer applied all at once.
+    /// `get` and other single-key calls lock only their key's shard, while
+    /// whole-cache operations such as `snapshot` or `transaction` take every
+    /// shard's lock. The capacity is split between shards, their shares
+    /// adding up to it, and each evicts once it holds its share, so a shard
+    /// may evict while others have room; with a capacity below `shards`,
+    /// only `capacity` shards are used. The extra shards start from
+    /// `Store::empty` of the configured store.
+    pub fn shards(mut self, shards: usize) -> Self {
+        self.config.shards = shards.max(1);
+        self
+    }
+
+    /// Applies fetch results `size` entries at a time, releasing the lock
+    /// between chunks so readers are not stalled by a huge snapshot. The
+    /// tradeoff is that readers may observe a partially-applied fetch.
+    pub fn fetch_chunk_size(mut self, size: usize) -> Self {
+        self.config.fetch_chunk_size = Some(size.max(1));
+        self
+    }
+
+    /// Sends the final contents to `sender` once the last worker exits,
+    /// whether its stream ended, it failed, or it was shut down gracefully,
+    /// e.g. to hand off to a successor cache.
+    pub fn on_shutdown_snapshot(mut self, sender: oneshot::Sender<HashMap<String, u64>>) -> Self {
+        self.shutdown_snapshot = Some(sender);
+        self
+    }
+
+    /// Seeds the cache with `initial` before any source starts; fetched and
+    /// streamed values then merge on top through the conflict policy.
+    pub fn initial(mut self, initial: HashMap<String, u64>) -> Self {
+        self.initial = initial;
+        self
+    }
+
+    /// Builds a cache with no sources attached; add them with
+    /// `update_in_background` or `add_source`.
+    pub fn build_empty(mut self) -> StreamCache {
+        let sweep_interval = self.config.sweep_interval;
+        let empty_warn_after = self.config.empty_warn_after;
+        let mut store = self.store;
+        store.clear();
+        let clock = Arc::default();
+        let seq = Arc::default();
+        let aggregate = self
+            .aggregate
+            .map(|aggregate| Arc::new(Mutex::new(aggregate)));
+        // Every shard holds at least one entry of the capacity.
+        if let Some(capacity) = self.config.capacity {
+            self.config.shards = self.config.shards.min(capacity);
+        }
+        let config = &self.config;
+        let mut stores: Vec<_> = (1..config.shards).map(|_| store.empty()).collect();
+        stores.insert(0, store);
+        let shards = stores
+            .into_iter()
+            .map(|values| {
+                Mutex::new(State {
+                    values,
+                    clock: Arc::clone(&clock),
+                    seq: Arc::clone(&seq),
+                    aggregate: aggregate.clone(),
+                    log_applied: config.json_log.is_some(),
+                    track_dirty: config.track_dirty,
+                    hit_window: HitWindow::new(config.hit_ratio_window),
+                    sliding_ttl: config.sliding_ttl,
+                    stale_grace: config.stale_grace.unwrap_or_default(),
+                    ..State::default()
+                })
+            })
+            .collect();
+        let instance = StreamCache {
+            api: (),
+            inner: Arc::new_cyclic(|this| Inner {
+                this: this.clone(),
+                shards,
+                shard_hasher: RandomState::new(),
+                config: self.config,
+                sources: Mutex::new(Vec::new()),
+                errors: Mutex::new(ErrorDedup {
+                    last: None,
+                    since: Instant::now(),
+                    suppressed: 0,
+                }),
+                shutdown: watch::channel(false).0,
+                pending_baselines: watch::channel(0).0,
+                workers: Mutex::new(Vec::new()),
+                running_workers: AtomicUsize::new(0),
+                emptied: Notify::new(),
+                revalidating: AtomicBool::new(false),
+                shutdown_snapshot: Mutex::new(self.shutdown_snapshot),
+                #[cfg(test)]
+                lock_count: Default::default(),
+            }),
+        };
+        if let Ok(mut shards) = instance.inner.lock() {
+            for (key, value) in self.initial {
+                let key = instance.inner.normalize(key);
+                instance.inner.apply_deferring_errors(
+                    shards.of(&key),
+                    key,
+                    value,
+                    None,
+                    None,
+                    Origin::Stream,
+                );
+            }
+        }
+        if let Some(interval) = sweep_interval {
+            spawn_sweeper(Arc::downgrade(&instance.inner), interval);
+        }
+        if let Some(grace) = empty_warn_after {
+            spawn_empty_check(Arc::downgrade(&instance.inner), grace);
+        }
+        instance
+    }
+
+    pub fn build<A: Api>(self, api: A) -> StreamCache<A> {
+        let instance = self.build_empty();
+        instance.update_in_background(api.clone());
+        StreamCache {
+            inner: instance.inner,
+            api,
+        }
+    }
+
+    /// Builds a cache whose `api` is driven by hand through the returned
+    /// `Driver` instead of a background worker, e.g. for deterministic
+    /// tests. The sweeper and `empty_warn_after` still run if configured.
+    pub fn build_manual<A: Api>(self, api: A) -> (StreamCache<A>, Driver) {
+        let instance = self.build_empty();
+        let driver = Driver::new(Arc::clone(&instance.inner), api.clone());
+        let instance = StreamCache {
+            inner: instance.inner,
+            api,
+        };
+        (instance, driver)
+    }
+
+    /// Runs the initial fetch before spawning anything, failing with
+    /// `CacheError::Fetch` if the `Api` cannot serve it. On success the
+    /// cache starts populated and only the subscription runs in the
+    /// background.
+    pub async fn try_build<A: Api>(self, api: A) -> Result<StreamCache<A>, CacheError> {
+        let fetched = api.fetch_updates().await.map_err(CacheError::Fetch)?;
+        let instance = self.build_empty();
+        instance.inner.apply_fetch(fetched, None).await?;
+        instance.spawn_worker(api.clone(), None, false, Vec::new());
+        Ok(StreamCache {
+            inner: instance.inner,
+            api,
+        })
+    }
+}
+
+/// A read-only copy of the cache taken by `StreamCache::checkpoint`. Clones
+/// share the same data.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    values: Arc<HashMap<String, u64>>,
+}
+
+impl Checkpoint {
+    pub fn get(&self, key: &str) -> Option<u64> {
+        self.values.get(key).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// What changed between this checkpoint and a later one.
+    pub fn diff(&self, new: &Checkpoint) -> CacheDiff {
+        diff(&self.values, &new.values)
+    }
+}
+
+/// The difference between two snapshots, as returned by `diff`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheDiff {
+    /// Keys only in the new snapshot, with their values.
+    pub added: HashMap<String, u64>,
+    /// Keys only in the old snapshot, with their last values.
+    pub removed: HashMap<String, u64>,
+    /// Keys in both whose value changed, with the old and new values.
+    pub changed: HashMap<String, (u64, u64)>,
+}
+
+/// Compares two snapshots, e.g. from `StreamCache::snapshot`.
+pub fn diff(old: &HashMap<String, u64>, new: &HashMap<String, u64>) -> CacheDiff {
+    let mut result = CacheDiff::default();
+    for (key, &value) in new {
+        match old.get(key) {
+            None => {
+                result.added.insert(key.clone(), value);
+            }
+            Some(&previous) if previous != value => {
+                result.changed.insert(key.clone(), (previous, value));
+            }
+            Some(_) => {}
+        }
+    }
+    for (key, &value) in old {
+        if !new.contains_key(key) {
+            result.removed.insert(key.clone(), value);
+        }
+    }
+    result
+}
+
+/// Direct access to the map behind a cache; see `StreamCache::shared_store`.
+#[derive(Clone)]
+pub struct SharedStore {
+    inner: Arc<Inner>,
+}
+
+impl SharedStore {
+    /// Takes the cache lock, every shard's with `StreamCacheBuilder::shards`.
+    /// The worker and every other cache call block until the guard is
+    /// dropped.
+    pub fn lock(&self) -> Result<StoreGuard<'_>, CacheError> {
+        Ok(StoreGuard {
+            shards: self.inner.lock()?,
+        })
+    }
+}
+
+/// The live entries of a cache's store, as `StreamCache::with_read` hands
+/// them out. Entries past their TTL are skipped, not removed.
+pub struct LiveView<'a> {
+    /// For the key normalizer.
+    inner: &'a Inner,
+    /// Each shard's store, with its metadata.
+    shards: Vec<(&'a dyn Store, &'a HashMap<String, EntryMeta>)>,
+    now: Instant,
+    grace: Duration,
+}
+
+impl LiveView<'_> {
+    fn is_live(&self, meta: &HashMap<String, EntryMeta>, key: &str) -> bool {
+        meta.get(key)
+            .is_none_or(|meta| !meta.is_gone(self.now, self.grace))
+    }
+
+    /// Looks up `key` as `StreamCache::get` does, through the key
+    /// normalizer.
+    pub fn get(&self, key: &str) -> Option<u64> {
+        let key = self.inner.lookup_key(key);
+        self.shards
+            .iter()
+            .find_map(|(values, meta)| values.get(&key).filter(|_| self.is_live(meta, &key)))
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, u64)> + '_ {
+        self.shards.iter().flat_map(move |(values, meta)| {
+            values
+                .iter()
+                .filter(move |(key, _)| self.is_live(meta, key))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// The entries with keys in `range`, in key order, or `None` if the
+    /// store is unordered.
+    pub fn range(&self, range: impl RangeBounds<String>) -> Option<Vec<(String, u64)>> {
+        let mut entries = Vec::new();
+        for (values, meta) in &self.shards {
+            let shard = values.range(
+                range.start_bound().map(String::as_str),
+                range.end_bound().map(String::as_str),
+            )?;
+            entries.extend(shard.into_iter().filter(|(key, _)| self.is_live(meta, key)));
+        }
+        if self.shards.len() > 1 {
+            entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        }
+        Some(entries)
+    }
+}
+
+/// The locked map of a `SharedStore`. Reads see values as stored, even
+/// when expired; writes take the same path as `StreamCache::insert` and
+/// `StreamCache::remove`.
+pub struct StoreGuard<'a> {
+    shards: Shards<'a>,
+}
+
+impl StoreGuard<'_> {
+    pub fn get(&self, key: &str) -> Option<u64> {
+        let shard = self.shards.inner.shard_of(key);
+        self.shards.guards[shard].values.get(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, u64)> + '_ {
+        self.shards.iter().flat_map(|state| state.values.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|state| state.values.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes like `StreamCache::insert`: through the key normalizer,
+    /// conflict policy, bounds, capacity and write deadline, and seen by
+    /// watchers and the aggregate.
+    pub fn insert(&mut self, key: String, value: u64) -> Result<Option<u64>, CacheError> {
+        let inner = self.shards.inner;
+        let key = inner.normalize(key);
+        let state = self.shards.of(&key);
+        let outcome = inner.apply(state, key, value, None, None, Origin::Stream);
+        if outcome.is_err() {
+            state.metrics.errors += 1;
+        }
+        Ok(outcome?.previous())
+    }
+
+    /// Removes `key` like `StreamCache::remove`, without notifying
+    /// watchers, but returning its stored value even if expired.
+    pub fn remove(&mut self, key: &str) -> Option<u64> {
+        let key = self.shards.inner.lookup_key(key);
+        let state = self.shards.of(&key);
+        state.meta.remove(&*key);
+        state.flaps.remove(&*key);
+        state.throttled.remove(&*key);
+        state.values.remove(&key)
+    }
+
+    /// Removes every entry for which `keep` returns false.
+    pub fn retain(&mut self, mut keep: impl FnMut(&str, u64) -> bool) {
+        let dropped: Vec<_> = self
+            .iter()
+            .filter(|(key, value)| !keep(key, *value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in dropped {
+            self.remove(&key);
+        }
+    }
+}
+
+/// Work for one shard's applier.
+enum ShardWork {
+    Apply(Update, Origin),
+    /// Answered once everything queued before it has been applied.
+    Flush(oneshot::Sender<()>),
+}
+
+/// A worker's per-shard appliers, with `StreamCacheBuilder::shards`. Each
+/// drains its own queue in order under its own shard's lock, so updates to
+/// one key keep their order while other shards apply in parallel.
+struct ShardAppliers {
+    queues: Vec<tokio::sync::mpsc::Sender<ShardWork>>,
+    tasks: Vec<JoinHandle<Result<(), CacheError>>>,
+}
+
+impl ShardAppliers {
+    /// Starts an applier per shard, or none with a single shard, which the
+    /// worker applies to directly.
+    fn spawn(
+        inner: &Arc<Inner>,
+        source: Option<SourceId>,
+        status: &Arc<Mutex<SourceStatus>>,
+        parent: Option<Span>,
+    ) -> Option<Self> {
+        if inner.shards.len() == 1 {
+            return None;
+        }
+        let mut queues = Vec::with_capacity(inner.shards.len());
+        let mut tasks = Vec::with_capacity(inner.shards.len());
+        for shard in 0..inner.shards.len() {
+            let (queue, mut work) = tokio::sync::mpsc::channel(inner.config.update_buffer);
+            let inner = Arc::clone(inner);
+            let source = source.clone();
+            let status = Arc::clone(status);
+            let parent = parent.clone();
+            tasks.push(tokio::spawn(async move {
+                while let Some(next) = work.recv().await {
+                    let mut state = match inner.lock_shard(shard) {
+                        Ok(state) => state,
+                        Err(err) => {
+                            record_status(&status, |status| status.last_error = Some(err.clone()));
+                            inner.report_error(err.clone());
+                            return Err(err);
+                        }
+                    };
+                    let mut next = Some(next);
+                    // Like the worker, applies what is already queued under
+                    // the same lock.
+                    for _ in 0..APPLY_BATCH {
+                        let Some(work) = next.take().or_else(|| work.try_recv().ok()) else {
+                            break;
+                        };
+                        match work {
+                            ShardWork::Apply(update, origin) => {
+                                let _span = inner.span("apply", parent.as_ref());
+                                inner.apply_to_shard(&mut state, update, source.as_ref(), origin);
+                            }
+                            ShardWork::Flush(done) => {
+                                drop(state);
+                                let _ = done.send(());
+                                break;
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            }));
+            queues.push(queue);
+        }
+        Some(ShardAppliers { queues, tasks })
+    }
+
+    /// Queues `work` for `shard`, returning false if its applier has failed.
+    async fn send(&self, shard: usize, work: ShardWork) -> bool {
+        self.queues[shard].send(work).await.is_ok()
+    }
+
+    /// Waits until every shard has applied what was queued so far,
+    /// returning false if an applier has failed.
+    async fn flush(&self) -> bool {
+        let mut pending = Vec::with_capacity(self.queues.len());
+        for shard in 0..self.queues.len() {
+            let (done, applied) = oneshot::channel();
+            if !self.send(shard, ShardWork::Flush(done)).await {
+                return false;
+            }
+            pending.push(applied);
+        }
+        for applied in pending {
+            if applied.await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Closes the queues and waits for the appliers to finish them,
+    /// returning the first error.
+    async fn finish(&mut self) -> Result<(), CacheError> {
+        self.queues.clear();
+        let mut result = Ok(());
+        for task in mem::take(&mut self.tasks) {
+            result = result.and(join_task(task).await);
+        }
+        result
+    }
+}
+
+/// What `Inner::prepare_streamed` left of a streamed update.
+enum Prepared {
+    /// The middleware dropped it.
+    Vetoed,
+    /// The async resolver applied it.
+    Applied,
+    /// It still has to be applied under the lock.
+    Pending(Update),
+}
+
+/// A delay that doubles on every retry, up to `MAX_BACKOFF`.
+struct Backoff {
+    initial: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    fn new(initial: Duration) -> Self {
+        Backoff {
+            initial,
+            next: initial,
+        }
+    }
+
+    /// Returns the current delay and doubles the next one.
+    fn step(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_BACKOFF);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+/// Awaits `task`, passing a panic on to the caller.
+async fn join_task<T>(task: JoinHandle<T>) -> T {
+    match task.await {
+        Ok(output) => output,
+        Err(err) => panic::resume_unwind(err.into_panic()),
+    }
+}
+
+/// Reports `CacheError::NoData` if the cache is still empty after `grace`.
+fn spawn_empty_check(inner: Weak<Inner>, grace: Duration) {
+    tokio::spawn(async move {
+        time::sleep(grace).await;
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let empty = match inner.lock() {
+            Ok(state) => state.is_empty(),
+            Err(_) => return,
+        };
+        if empty {
+            inner.report_error(CacheError::NoData);
+        }
+    });
+}
+
+/// Periodically sweeps expired entries until the cache is dropped.
+fn spawn_sweeper(inner: Weak<Inner>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(inner) = inner.upgrade() else {
+                break;
+            };
+            // One shard at a time, so the others stay writable meanwhile.
+            for shard in 0..inner.shards.len() {
+                let Ok(mut state) = inner.lock_shard(shard) else {
+                    return;
+                };
+                state.sweep(Instant::now());
+                if state.values.capacity() > COMPACT_RATIO * state.values.len().max(16) {
+                    state.shrink_to_fit();
+                }
+            }
+        }
+    });
+}
+
+/// Wraps an `Api` for `StreamCacheBuilder::serialize_api_calls`, holding
+/// `lock` across every call. The streams it returns are not guarded.
+#[derive(Clone)]
+struct SerializedApi<A> {
+    api: A,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+#[async_trait]
+impl<A: Api> Api for SerializedApi<A> {
+    async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+        let _guard = self.lock.lock().await;
+        self.api.fetch().await
+    }
+
+    async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+        let _guard = self.lock.lock().await;
+        self.api.subscribe().await
+    }
+
+    async fn subscribe_keys(
+        &self,
+        keys: &[City],
+    ) -> BoxStream<Result<(City, Temperature), String>> {
+        let _guard = self.lock.lock().await;
+        self.api.subscribe_keys(keys).await
+    }
+
+    async fn subscribe_updates(&self) -> BoxStream<Result<Update, String>> {
+        let _guard = self.lock.lock().await;
+        self.api.subscribe_updates().await
+    }
+
+    async fn fetch_page(
+        &self,
+        cursor: Option<String>,
+    ) -> Result<(HashMap<City, Temperature>, Option<String>), CacheError> {
+        let _guard = self.lock.lock().await;
+        self.api.fetch_page(cursor).await
+    }
+
+    async fn fetch_updates(&self) -> Result<Vec<Update>, String> {
+        let _guard = self.lock.lock().await;
+        self.api.fetch_updates().await
+    }
+}
+
+/// The input of `StreamCache::from_stream`.
+pub type EntryStream = BoxStream<'static, Result<(String, u64), CacheError>>;
+
+/// An `Api` over a single prebuilt stream, for `StreamCache::from_stream`.
+/// It has nothing to fetch, and resubscribing after the stream ends yields
+/// nothing more.
+#[derive(Clone)]
+struct StreamApi {
+    stream: Arc<Mutex<Option<EntryStream>>>,
+}
+
+#[async_trait]
+impl Api for StreamApi {
+    async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+        Ok(HashMap::new())
+    }
+
+    async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+        let stream = self.stream.lock().unwrap_or_else(|e| e.into_inner()).take();
+        match stream {
+            Some(stream) => stream
+                .map(|item| {
+                    item.map_err(|err| match err {
+                        CacheError::Subscribe(err) => err,
+                        err => err.to_string(),
+                    })
+                })
+                .boxed(),
+            None => futures::stream::empty().boxed(),
+        }
+    }
+}
+
+/// Steps a cache built with `StreamCacheBuilder::build_manual` one update at
+/// a time, in place of the background worker. Streamed updates go through
+/// the same middleware, conflict policy and subscription error policy.
+/// Timing is left to the caller, so `idle_timeout`, the wait of
+/// `WritePriority::FetchFirst`, `initial_fetch_retries` and the resubscribe
+/// backoff do not apply, and neither does `snapshot_from_subscribe`.
+pub struct Driver {
+    steps: UnboundedSender<oneshot::Sender<bool>>,
+    /// Owns the `Api` and its open subscription, and runs one step per
+    /// message on `steps` whenever `poll_once` polls it.
+    run: BoxFuture<'static, ()>,
+}
+
+impl Driver {
+    fn new(inner: Arc<Inner>, api: impl Api) -> Self {
+        let (steps, mut requests) = mpsc::unbounded::<oneshot::Sender<bool>>();
+        let run = async move {
+            let status = Mutex::new(SourceStatus::default());
+            let mut fetch_pending = !inner.config.skip_initial_fetch;
+            let mut subscription = None;
+            let mut ended = false;
+            let resolver = inner.stream_resolver();
+            while let Some(done) = requests.next().await {
+                let progressed = if mem::take(&mut fetch_pending) {
+                    inner.fetch_from(&api, None, &status).await;
+                    true
+                } else if ended {
+                    false
+                } else {
+                    let stream = match &mut subscription {
+                        Some(stream) => stream,
+                        None => subscription.insert(inner.subscribe(&api).await),
+                    };
+                    match stream.next().await {
+                        Some(Ok(update)) => {
+                            // A resync clears the cache; the next step
+                            // fetches again.
+                            fetch_pending = update == Update::Resync;
+                            match inner
+                                .prepare_streamed(update, None, resolver.as_ref(), None)
+                                .await
+                            {
+                                Ok(Prepared::Pending(update)) => {
+                                    if let Ok(mut state) = inner.lock() {
+                                        inner.apply_update(
+                                            &mut state,
+                                            update,
+                                            None,
+                                            Origin::Stream,
+                                        );
+                                    }
+                                }
+                                Ok(Prepared::Vetoed | Prepared::Applied) => {}
+                                // Like a worker, the driver stops applying.
+                                Err(err) => {
+                                    inner.report_error(err);
+                                    ended = true;
+                                }
+                            }
+                            true
+                        }
+                        Some(Err(err)) => {
+                            inner.report_error(err.clone());
+                            match inner.config.subscription_error_policy.for_error(&err) {
+                                SubscriptionErrorPolicy::Skip => {}
+                                // The next step subscribes again.
+                                SubscriptionErrorPolicy::Reconnect => subscription = None,
+                                SubscriptionErrorPolicy::Fail => ended = true,
+                            }
+                            true
+                        }
+                        None => {
+                            ended = true;
+                            false
+                        }
+                    }
+                };
+                let _ = done.send(progressed);
+            }
+        }
+        .boxed();
+        Driver { steps, run }
+    }
+
+    /// Processes exactly one thing: the initial fetch first (unless
+    /// `skip_initial_fetch` is set) or the fetch after a resync, otherwise
+    /// the next stream item, awaiting it if none is ready yet. Returns false
+    /// once the stream has ended or an error stopped the driver.
+    pub async fn poll_once(&mut self) -> bool {
+        let (done, mut finished) = oneshot::channel();
+        if self.steps.unbounded_send(done).is_err() {
+            return false;
+        }
+        tokio::select! {
+            _ = &mut self.run => false,
+            progressed = &mut finished => progressed.unwrap_or(false),
+        }
+    }
+}
+
+/// A cache fed by an `Api` of type `A`, which `api` hands back. Caches
+/// built without one, by `build_empty` or `from_stream`, have `A = ()`.
+pub struct StreamCache<A = ()> {
+    inner: Arc<Inner>,
+    api: A,
+}
+
+impl StreamCache {
+    pub fn builder() -> StreamCacheBuilder {
+        StreamCacheBuilder::default()
+    }
+
+    /// Runs only the subscription half over `stream`, with no `Api` and no
+    /// initial fetch, e.g. for tests and quick experiments. The worker
+    /// stops once the stream ends.
+    pub fn from_stream(stream: EntryStream) -> Self {
+        let api = StreamApi {
+            stream: Arc::new(Mutex::new(Some(stream))),
+        };
+        let instance = Self::builder().skip_initial_fetch(true).build_empty();
+        instance.update_in_background(api);
+        instance
+    }
+}
+
+impl<A: Api> StreamCache<A> {
+    pub fn new(api: A) -> Self {
+        StreamCache::builder().build(api)
+    }
+
+    /// See `StreamCacheBuilder::build_manual`.
+    pub fn new_manual(api: A) -> (Self, Driver) {
+        StreamCache::builder().build_manual(api)
+    }
+
+    /// Creates a cache seeded with `initial`; see `StreamCacheBuilder::initial`.
+    pub fn with_initial(api: A, initial: HashMap<String, u64>) -> Self {
+        StreamCache::builder().initial(initial).build(api)
+    }
+
+    /// Creates a cache that treats `idle` without stream items as a stall;
+    /// see `StreamCacheBuilder::idle_timeout`.
+    pub fn with_idle_timeout(api: A, idle: Duration) -> Self {
+        StreamCache::builder().idle_timeout(idle).build(api)
+    }
+
+    /// See `StreamCacheBuilder::try_build`.
+    pub async fn try_new(api: A) -> Result<Self, CacheError> {
+        StreamCache::builder().try_build(api).await
+    }
+}
+
+impl<A> StreamCache<A> {
+    /// The `Api` the cache was built with (or last switched to by
+    /// `set_api`), to reuse the same client for other calls.
+    pub fn api(&self) -> &A {
+        &self.api
+    }
+
+    /// Returns `None` for missing keys, and also when the lock is poisoned
+    /// under `PoisonPolicy::Error`; use `try_get` to tell the two apart.
+    pub fn get(&self, key: &str) -> Option<u64> {
+        self.try_get(key).ok().flatten()
+    }
+
+    pub fn try_get(&self, key: &str) -> Result<Option<u64>, CacheError> {
+        let key = self.inner.lookup_key(key);
+        let mut state = self.inner.lock_key(&key)?;
+        Ok(self.inner.read(&mut state, &key, Instant::now()))
+    }
+
+    /// Like `get`, with the same decay and stale reads, but leaves no trace:
+    /// no hit/miss counted, no LRU bump, no TTL extension and no
+    /// `stale_while_revalidate` refresh, so diagnostic reads do not skew
+    /// production stats.
+    pub fn peek(&self, key: &str) -> Option<u64> {
+        let key = self.inner.lookup_key(key);
+        let state = self.inner.lock_key(&key).ok()?;
+        self.inner.peek(&state, &key, Instant::now())
+    }
+
+    /// Reads `key` and maps it with `f` under the lock, e.g. to convert
+    /// units.
+    pub fn get_as<T>(&self, key: &str, f: impl FnOnce(u64) -> T) -> Option<T> {
+        let key = self.inner.lookup_key(key);
+        let mut state = self.inner.lock_key(&key).ok()?;
+        self.inner.read(&mut state, &key, Instant::now()).map(f)
+    }
+
+    /// Resolves once every source has its baseline in: the initial fetch
+    /// finished (or failed), or the stream sent `Update::SnapshotComplete`
+    /// under `snapshot_from_subscribe`.
+    pub async fn wait_ready(&self) {
+        let mut pending = self.inner.pending_baselines.subscribe();
+        let _ = pending.wait_for(|pending| *pending == 0).await;
+    }
+
+    /// Stops every worker from reading its stream, then waits until the
+    /// updates already queued have been applied and the workers have exited.
+    /// Sources added afterwards stop straight away.
+    pub async fn shutdown_graceful(&self) {
+        self.inner.shutdown.send_replace(true);
+        let workers = match self.inner.config.poison_policy.lock(&self.inner.workers) {
+            Ok(mut workers) => mem::take(&mut *workers),
+            Err(_) => return,
+        };
+        for worker in workers {
+            let _ = worker.await;
+        }
+    }
+
+    /// Waits for every worker to finish, as they do once bounded streams
+    /// end, and returns the first terminal error: a stream error under
+    /// `SubscriptionErrorPolicy::Fail`, or a poisoned lock under
+    /// `PoisonPolicy::Error`. A worker panic is resumed here.
+    pub async fn join(self) -> Result<(), CacheError> {
+        let workers = mem::take(&mut *self.inner.config.poison_policy.lock(&self.inner.workers)?);
+        let mut result = Ok(());
+        for worker in workers {
+            let outcome = join_task(worker).await;
+            result = result.and(outcome);
+        }
+        result
+    }
+
+    /// Like `get`, but asks the fallback store on a miss.
+    pub async fn get_or_fallback(&self, key: &str) -> Option<u64> {
+        let key = self.inner.lookup_key(key);
+        if let Some(value) = self.get(&key) {
+            return Some(value);
+        }
+        let value = self.inner.config.fallback.as_ref()?.get(&key).await?;
+        if self.inner.config.promote_fallback {
+            if let Ok(mut state) = self.inner.lock_key(&key) {
+                self.inner.apply_deferring_errors(
+                    &mut state,
+                    key.to_string(),
+                    value,
+                    None,
+                    None,
+                    Origin::Fetch,
+                );
+            }
+        }
+        Some(value)
+    }
+
+    /// Like `get`, but also returns how long ago the value was updated, so
+    /// callers can apply their own freshness rules within the TTL.
+    pub fn get_with_age(&self, key: &str) -> Option<(u64, Duration)> {
+        let key = self.inner.lookup_key(key);
+        let mut state = self.inner.lock_key(&key).ok()?;
+        let now = Instant::now();
+        let value = self.inner.read(&mut state, &key, now)?;
+        let updated_at = state.meta.get(&*key)?.updated_at;
+        Some((value, now - updated_at))
+    }
+
+    /// The aggregate set up with `StreamCacheBuilder::with_aggregate`, or
+    /// `None` if there is none of type `T`.
+    pub fn aggregate<T: Clone + 'static>(&self) -> Option<T> {
+        let state = self.inner.lock_shard(0).ok()?;
+        let aggregate = state.aggregate.as_ref()?;
+        let aggregate = aggregate.lock().unwrap_or_else(|e| e.into_inner());
+        aggregate.value.downcast_ref().cloned()
+    }
+
+    /// The most recent error reported by a worker or `insert`.
+    pub fn last_error(&self) -> Option<CacheError> {
+        let state = self.inner.lock_shard(0).ok()?;
+        state.last_error.clone()
+    }
+
+    /// The keys whose value changed since the previous call, sorted, for
+    /// pull-based change detection. Changes count as `watch` reports them,
+    /// so removals and expiry are not included.
+    ///
+    /// Tracking is off by default: without
+    /// `StreamCacheBuilder::track_dirty(true)` this returns
+    /// `CacheError::DirtyTrackingOff`, so the missing option does not pass
+    /// for "nothing changed".
+    pub fn take_dirty(&self) -> Result<Vec<String>, CacheError> {
+        if !self.inner.config.track_dirty {
+            return Err(CacheError::DirtyTrackingOff);
+        }
+        let mut state = self.inner.lock()?;
+        let mut dirty: Vec<_> = state
+            .iter_mut()
+            .flat_map(|shard| shard.dirty.drain().collect::<Vec<_>>())
+            .collect();
+        dirty.sort_unstable();
+        Ok(dirty)
+    }
+
+    /// Up to `n` of the latest worker errors with when they were reported,
+    /// newest first. Only the last 16 are kept.
+    pub fn recent_errors(&self, n: usize) -> Vec<(Instant, CacheError)> {
+        let Ok(state) = self.inner.lock_shard(0) else {
+            return Vec::new();
+        };
+        state.error_history.iter().rev().take(n).cloned().collect()
+    }
+
+    /// Reads the hit/miss/update/error counters.
+    pub fn metrics(&self) -> Metrics {
+        self.inner
+            .lock()
+            .map(|state| {
+                state
+                    .iter()
+                    .fold(Metrics::default(), |sum, shard| sum.merge(shard.metrics))
+            })
+            .unwrap_or_default()
+    }
+
+    /// Zeroes all counters at once, leaving the cached data alone.
+    pub fn reset_metrics(&self) {
+        if let Ok(mut state) = self.inner.lock() {
+            for shard in state.iter_mut() {
+                shard.metrics = Metrics::default();
+                shard.hit_window.buckets.clear();
+            }
+        }
+    }
+
+    /// The share of reads that hit over the last `hit_ratio_window`, or 0.0
+    /// if there were none; unlike `metrics()`, older reads drop out.
+    pub fn windowed_hit_ratio(&self) -> f64 {
+        let Ok(mut state) = self.inner.lock() else {
+            return 0.0;
+        };
+        let now = Instant::now();
+        let (hits, misses) = state
+            .iter_mut()
+            .map(|shard| shard.hit_window.counts(now))
+            .fold((0, 0), |(hits, misses), shard| {
+                (hits + shard.0, misses + shard.1)
+            });
+        if hits + misses == 0 {
+            return 0.0;
+        }
+        hits as f64 / (hits + misses) as f64
+    }
+
+    /// Writes `value` as if it had been streamed, subject to the conflict
+    /// policy and capacity. Returns the previous value.
+    pub fn insert(&self, key: impl Into<String>, value: u64) -> Result<Option<u64>, CacheError> {
+        Ok(self.insert_with_outcome(key, value)?.previous())
+    }
+
+    /// Like `insert`, but reports what the conflict policy and capacity
+    /// limit decided.
+    pub fn insert_with_outcome(
+        &self,
+        key: impl Into<String>,
+        value: u64,
+    ) -> Result<InsertOutcome, CacheError> {
+        let key = self.inner.normalize(key.into());
+        let mut state = self.inner.lock_key(&key)?;
+        let outcome = self
+            .inner
+            .apply(&mut state, key, value, None, None, Origin::Stream);
+        if outcome.is_err() {
+            state.metrics.errors += 1;
+        }
+        outcome
+    }
+
+    /// Adds `by` to the value of `key`, a missing key counting as 0, under a
+    /// single lock so concurrent increments are not lost. The saturated sum
+    /// is stored as is, keeping the entry's TTL: it bypasses the conflict
+    /// policy, bounds, flap suppression and throttle, any of which could
+    /// drop or hold back an increment. A new key still counts toward the
+    /// capacity. Returns the value stored, or `CacheError::PastDeadline`
+    /// after the `with_write_deadline` cutoff.
+    pub fn increment(&self, key: &str, by: u64) -> Result<u64, CacheError> {
+        let key = self.inner.normalize(key.to_string());
+        let mut state = self.inner.lock_key(&key)?;
+        let now = Instant::now();
+        let old = state.get_live(&key, now);
+        let new = old.unwrap_or(0).saturating_add(by);
+        if self.inner.past_deadline() {
+            return Err(CacheError::PastDeadline { key, value: new });
+        }
+        let ttl = match old {
+            Some(_) => state.meta[&key].ttl,
+            None => {
+                self.inner.make_room(&mut state, &key);
+                None
+            }
+        };
+        state.insert(key, new, self.inner.entry_meta(None, ttl, now));
+        Ok(new)
+    }
+
+    /// Writes every entry under a single lock, each merged as `insert` would
+    /// through the conflict policy, bounds and capacity. Errors go to the
+    /// error handler.
+    pub fn extend(&self, entries: impl IntoIterator<Item = (String, u64)>) {
+        let Ok(mut shards) = self.inner.lock() else {
+            return;
+        };
+        for (key, value) in entries {
+            let key = self.inner.normalize(key);
+            self.inner.apply_deferring_errors(
+                shards.of(&key),
+                key,
+                value,
+                None,
+                None,
+                Origin::Stream,
+            );
+        }
+    }
+
+    /// Swaps the whole contents for `new` under a single lock, so readers see
+    /// either the old or the new set. Values bypass the conflict policy and
+    /// capacity; watchers see the changed and added keys. After the
+    /// `with_write_deadline` cutoff it does nothing.
+    pub fn replace_all(&self, new: HashMap<String, u64>) {
+        if self.inner.past_deadline() {
+            return;
+        }
+        let new: HashMap<_, _> = new
+            .into_iter()
+            .map(|(key, value)| (self.inner.normalize(key), value))
+            .collect();
+        let Ok(mut shards) = self.inner.lock() else {
+            return;
+        };
+        for state in shards.iter_mut() {
+            let state = &mut **state;
+            state.values.retain(&mut |key, _| new.contains_key(key));
+            state.meta.retain(|key, _| new.contains_key(key));
+            state.flaps.clear();
+            state.throttled.clear();
+        }
+        let now = Instant::now();
+        for (key, value) in new {
+            let state = shards.of(&key);
+            state.insert(key, value, self.inner.entry_meta(None, None, now));
+        }
+    }
+
+    /// Runs `f` over the whole store under the cache lock, e.g. for a one-off
+    /// aggregation without the allocation of `snapshot`. `f` gets a
+    /// read-only `LiveView` of the store rather than a `&HashMap`, since the
+    /// store is pluggable (see `StreamCacheBuilder::store`). The view skips
+    /// expired entries (keeping stale ones under `stale_while_revalidate`)
+    /// without sweeping them, so reading fires no `on_expire`; it is empty
+    /// if the lock is poisoned under `PoisonPolicy::Error`. `f` must not
+    /// block or await, as every reader and writer waits on it.
+    pub fn with_read<R>(&self, f: impl FnOnce(&LiveView<'_>) -> R) -> R {
+        let now = Instant::now();
+        let Ok(state) = self.inner.lock() else {
+            return f(&LiveView {
+                inner: &self.inner,
+                shards: Vec::new(),
+                now,
+                grace: Duration::ZERO,
+            });
+        };
+        f(&LiveView {
+            inner: &self.inner,
+            shards: state
+                .iter()
+                .map(|shard| (&*shard.values, &shard.meta))
+                .collect(),
+            now,
+            grace: state.first().stale_grace,
+        })
+    }
+
+    /// Applies every op under a single lock, so readers see the cache either
+    /// before or after the whole transaction. Sets bypass the conflict
+    /// policy and capacity as in `replace_all`, but not the value bounds: a
+    /// value rejected with `OnViolation::Error` fails the transaction before
+    /// anything changes, as does any set after the `with_write_deadline`
+    /// cutoff, with `CacheError::PastDeadline`.
+    pub fn transaction(&self, ops: Vec<Op>) -> Result<(), CacheError> {
+        let mut checked = Vec::with_capacity(ops.len());
+        for op in ops {
+            match op {
+                Op::Set(key, value) => {
+                    let key = self.inner.normalize(key);
+                    if self.inner.past_deadline() {
+                        return Err(CacheError::PastDeadline { key, value });
+                    }
+                    // Dropped values (out of bounds, the sentinel) leave the key alone.
+                    if let Some(value) = self.inner.bound(&key, value)? {
+                        checked.push((key, Some(value)));
+                    }
+                }
+                Op::Remove(key) => checked.push((self.inner.normalize(key), None)),
+            }
+        }
+        let mut shards = self.inner.lock()?;
+        let now = Instant::now();
+        for (key, value) in checked {
+            let state = shards.of(&key);
+            match value {
+                Some(value) => {
+                    state.insert(key, value, self.inner.entry_meta(None, None, now));
+                }
+                None => {
+                    state.meta.remove(&key);
+                    state.flaps.remove(&key);
+                    state.throttled.remove(&key);
+                    state.values.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `f` on the entry for `key` while holding the cache lock, so a
+    /// read-modify-write cannot race the worker. `f` gets `None` if the key
+    /// is missing (or the lock is poisoned under `PoisonPolicy::Error`).
+    /// A changed value is written like any other write, keeping the entry's
+    /// TTL; after the `with_write_deadline` cutoff it is dropped and
+    /// reported as `CacheError::PastDeadline`. `f` must not block or await,
+    /// as every reader and writer waits on it.
+    pub fn with_entry<R>(&self, key: &str, f: impl FnOnce(Option<&mut u64>) -> R) -> R {
+        let key = self.inner.lookup_key(key);
+        let Ok(mut state) = self.inner.lock_key(&key) else {
+            return f(None);
+        };
+        let now = Instant::now();
+        let Some(old) = state.read(&key, now) else {
+            return f(None);
+        };
+        let mut value = old;
+        let result = f(Some(&mut value));
+        if value != old && self.inner.past_deadline() {
+            state.errors.push(CacheError::PastDeadline {
+                key: key.into_owned(),
+                value,
+            });
+        } else if value != old {
+            let ttl = state.meta[&*key].ttl;
+            let meta = self.inner.entry_meta(None, ttl, now);
+            state.insert(key.into_owned(), value, meta);
+        }
+        result
+    }
+
+    /// The configured cache-wide TTL.
+    pub fn ttl(&self) -> Option<Duration> {
+        self.inner.config.ttl
+    }
+
+    /// The configured maximum number of entries.
+    pub fn capacity(&self) -> Option<usize> {
+        self.inner.config.capacity
+    }
+
+    /// The number of unexpired entries.
+    pub fn len(&self) -> usize {
+        let Ok(state) = self.inner.lock() else {
+            return 0;
+        };
+        state.live(Instant::now()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies the unexpired entries.
+    pub fn snapshot(&self) -> HashMap<String, u64> {
+        let Ok(state) = self.inner.lock() else {
+            return HashMap::new();
+        };
+        state.live_snapshot(Instant::now())
+    }
+
+    /// Exempts `key` from capacity eviction, whether or not it is cached
+    /// yet. It can still expire or be removed.
+    pub fn pin(&self, key: &str) {
+        let key = self.inner.lookup_key(key).into_owned();
+        if let Ok(mut state) = self.inner.lock_key(&key) {
+            state.pinned.insert(key);
+        }
+    }
+
+    /// Makes `key` evictable again.
+    pub fn unpin(&self, key: &str) {
+        let key = self.inner.lookup_key(key);
+        if let Ok(mut state) = self.inner.lock_key(&key) {
+            state.pinned.remove(&*key);
+        }
+    }
+
+    /// Copies the unexpired entries `pred` accepts, under one lock, e.g.
+    /// every city at or above some temperature.
+    pub fn filter(&self, pred: impl Fn(&str, u64) -> bool) -> HashMap<String, u64> {
+        let Ok(state) = self.inner.lock() else {
+            return HashMap::new();
+        };
+        state
+            .live(Instant::now())
+            .filter(|(key, value, _)| pred(key, *value))
+            .map(|(key, value, _)| (key.clone(), value))
+            .collect()
+    }
+
+    /// The unexpired entries with keys in `range`, in key order, e.g.
+    /// `"Lon".to_string().."Loo".to_string()` for every key starting with
+    /// "Lon". Needs an ordered store such as `BTreeStore`; with the default
+    /// `HashMap` this returns `CacheError::Unordered`.
+    pub fn range(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, u64)>, CacheError> {
+        let state = self.inner.lock()?;
+        let now = Instant::now();
+        let mut entries = Vec::new();
+        for shard in state.iter() {
+            let shard_entries = shard
+                .values
+                .range(
+                    range.start_bound().map(String::as_str),
+                    range.end_bound().map(String::as_str),
+                )
+                .ok_or(CacheError::Unordered)?;
+            entries.extend(
+                shard_entries
+                    .into_iter()
+                    .filter(|(key, _)| !shard.meta[key].is_expired(now)),
+            );
+        }
+        if state.guards.len() > 1 {
+            entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        }
+        Ok(entries)
+    }
+
+    /// Writes the live entries as CSV rows of `key,value`, sorted by key,
+    /// after a `key,value` header. The rows come from one snapshot.
+    pub fn to_csv(&self, writer: impl Write) -> io::Result<()> {
+        self.to_csv_with_header(writer, Some("key,value"))
+    }
+
+    /// Like `to_csv`, with a custom header row, or none.
+    pub fn to_csv_with_header(
+        &self,
+        mut writer: impl Write,
+        header: Option<&str>,
+    ) -> io::Result<()> {
+        let mut entries: Vec<_> = self.snapshot().into_iter().collect();
+        entries.sort_unstable();
+        if let Some(header) = header {
+            writeln!(writer, "{header}")?;
+        }
+        for (key, value) in entries {
+            writeln!(writer, "{},{value}", csv_field(&key))?;
+        }
+        writer.flush()
+    }
+
+    /// Freezes the current contents into a `Checkpoint` that keeps reporting
+    /// them while the live cache moves on.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            values: Arc::new(self.snapshot()),
+        }
+    }
+
+    /// Releases the memory left over from a spike of entries that have
+    /// since expired or been evicted. The sweeper also does this on its own
+    /// once the map is mostly empty.
+    pub fn shrink_to_fit(&self) {
+        if let Ok(mut state) = self.inner.lock() {
+            for shard in state.iter_mut() {
+                shard.shrink_to_fit();
+            }
+        }
+    }
+
+    /// Advanced and unstable: a handle to the map itself, guarded by the
+    /// cache's own lock, for integrations that cannot afford `snapshot`'s
+    /// copy. Values are read as stored, even when expired, while writes
+    /// take the usual path, so watchers, the aggregate and the write
+    /// deadline see them. Hold the guard briefly: the worker waits on it.
+    pub fn shared_store(&self) -> SharedStore {
+        SharedStore {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    /// The `n` most recently updated live entries, newest first. An update
+    /// repeating the stored value counts when it refreshes the TTL.
+    pub fn recent(&self, n: usize) -> Vec<(String, u64)> {
+        let Ok(state) = self.inner.lock() else {
+            return Vec::new();
+        };
+        let mut entries: Vec<_> = state
+            .live(Instant::now())
+            .map(|(key, value, meta)| (meta.written, key, value))
+            .collect();
+        entries.sort_unstable_by(|a, b| b.cmp(a));
+        entries
+            .into_iter()
+            .take(n)
+            .map(|(_, key, value)| (key.clone(), value))
+            .collect()
+    }
+
+    /// How many times the live entry for `key` has been written.
+    pub fn update_count(&self, key: &str) -> Option<u64> {
+        let key = self.inner.lookup_key(key);
+        let state = self.inner.lock_key(&key).ok()?;
+        let meta = state.meta.get(&*key)?;
+        (!meta.is_expired(Instant::now())).then_some(meta.updates)
+    }
+
+    /// The `n` live keys written most often, with their counts, busiest
+    /// first; ties go to the smaller key.
+    pub fn hot_keys(&self, n: usize) -> Vec<(String, u64)> {
+        let Ok(state) = self.inner.lock() else {
+            return Vec::new();
+        };
+        let mut entries: Vec<_> = state
+            .live(Instant::now())
+            .map(|(key, _, meta)| (key.clone(), meta.updates))
+            .collect();
+        entries.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        entries.truncate(n);
+        entries
+    }
+
+    /// Up to `n` live entries picked uniformly at random, all read under one
+    /// lock.
+    ///
+    /// The randomness comes from a small built-in SplitMix64 generator, not
+    /// the `rand` crate. Each call seeds it from the standard library's
+    /// per-process hasher keys, so samples cannot be reproduced from a
+    /// seed. It is fine for spot checks, but it is not cryptographically
+    /// secure.
+    pub fn sample(&self, n: usize) -> Vec<(String, u64)> {
+        let Ok(state) = self.inner.lock() else {
+            return Vec::new();
+        };
+        let mut rng = SampleRng::new();
+        let stored = state.iter().map(|shard| shard.values.len()).sum::<usize>();
+        let mut sample = Vec::with_capacity(n.min(stored));
+        // Reservoir sampling: the i-th entry replaces a kept one with
+        // probability n / (i + 1).
+        for (seen, (key, value, _)) in state.live(Instant::now()).enumerate() {
+            if sample.len() < n {
+                sample.push((key.clone(), value));
+            } else {
+                let slot = rng.below(seen + 1);
+                if slot < n {
+                    sample[slot] = (key.clone(), value);
+                }
+            }
+        }
+        sample
+    }
+
+    /// Returns the source that last won `key`, if it was written by a source
+    /// added through `add_source`.
+    pub fn source_of(&self, key: &str) -> Option<SourceId> {
+        let key = self.inner.lookup_key(key);
+        let state = self.inner.lock_key(&key).ok()?;
+        state.meta.get(&*key)?.source.clone()
+    }
+
+    /// Removes `key`, returning its value if it was live. Watchers are not
+    /// notified.
+    pub fn remove(&self, key: &str) -> Option<u64> {
+        let key = self.inner.lookup_key(key);
+        let mut state = self.inner.lock_key(&key).ok()?;
+        let meta = state.meta.remove(&*key)?;
+        state.flaps.remove(&*key);
+        state.throttled.remove(&*key);
+        let value = state.values.remove(&key)?;
+        (!meta.is_expired(Instant::now())).then_some(value)
+    }
+
+    /// Expires `key` at `when` instead of after its TTL, e.g. for a value
+    /// with a known validity window. Reads stop returning it from then on
+    /// and the sweeper removes it; a later write to `key` replaces the
+    /// deadline with the usual TTL. Returns whether `key` was live.
+    pub fn expire_at(&self, key: &str, when: Instant) -> bool {
+        let key = self.inner.lookup_key(key);
+        let Ok(mut state) = self.inner.lock_key(&key) else {
+            return false;
+        };
+        let now = Instant::now();
+        match state.meta.get_mut(&*key) {
+            Some(meta) if !meta.is_expired(now) => {
+                meta.expires_at = Some(when);
+                meta.ttl = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Streams every future change to the cache.
+    pub fn watch(&self) -> Watch {
+        self.subscribe_watcher(false)
+    }
+
+    /// Like `watch`, but first emits the current contents as events with
+    /// `old: None`. The snapshot is taken under the same lock that registers
+    /// the watcher, so it always precedes, and never misses, a live change.
+    pub fn watch_with_snapshot(&self) -> Watch {
+        self.subscribe_watcher(true)
+    }
+
+    /// Like `watch`, but emits at most one batch per `interval`, holding
+    /// every key changed in it once: `old` is the value before the first
+    /// change, `new` the latest. Quiet intervals emit nothing.
+    pub fn watch_batched(&self, interval: Duration) -> BatchedWatch {
+        let mut ticker = time::interval_at(Instant::now() + interval, interval);
+        ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        BatchedWatch::new(self.watch(), ticker)
+    }
+
+    /// The number of attached `watch`/`updates` streams.
+    pub fn watcher_count(&self) -> usize {
+        let Ok(state) = self.inner.lock_shard(0) else {
+            return 0;
+        };
+        state
+            .watchers
+            .iter()
+            .filter(|watcher| !watcher.is_closed())
+            .count()
+    }
+
+    /// Resolves with the value of `key` once `pred` holds for it, checking
+    /// the current value first and then every change. Returns `None` only
+    /// if the cache stops delivering changes (a poisoned lock under
+    /// `PoisonPolicy::Error`).
+    pub async fn wait_for(&self, key: &str, pred: impl Fn(u64) -> bool) -> Option<u64> {
+        let key = self.inner.lookup_key(key);
+        // Watch before reading, so a change in between is not missed.
+        let mut watch = self.watch();
+        if let Some(value) = self.get(&key).filter(|value| pred(*value)) {
+            return Some(value);
+        }
+        while let Some(event) = watch.next().await {
+            if event.key == *key && pred(event.new) {
+                return Some(event.new);
+            }
+        }
+        None
+    }
+
+    /// Like `wait_for`, but gives up with `None` after `timeout`.
+    pub async fn wait_for_timeout(
+        &self,
+        key: &str,
+        pred: impl Fn(u64) -> bool,
+        timeout: Duration,
+    ) -> Option<u64> {
+        time::timeout(timeout, self.wait_for(key, pred))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Resolves with true once no live entries are left, e.g. after
+    /// `clear` or once every entry expired, or with false after `timeout`.
+    /// Wakes on removals and on the next expiry deadline instead of polling.
+    pub async fn wait_empty(&self, timeout: Duration) -> bool {
+        time::timeout(timeout, self.drained())
+            .await
+            .unwrap_or(false)
+    }
+
+    async fn drained(&self) -> bool {
+        loop {
+            // Register before checking, so a removal in between is not missed.
+            let emptied = self.inner.emptied.notified();
+            let next_expiry = {
+                let Ok(state) = self.inner.lock() else {
+                    return false;
+                };
+                let mut live = state.live(Instant::now()).map(|(_, _, meta)| meta);
+                let Some(first) = live.next() else {
+                    return true;
+                };
+                live.chain([first]).filter_map(|meta| meta.expires_at).min()
+            };
+            match next_expiry {
+                Some(at) => {
+                    tokio::select! {
+                        _ = emptied => {}
+                        _ = time::sleep_until(at) => {}
+                    }
+                }
+                None => emptied.await,
+            }
+        }
+    }
+
+    /// The live change stream, for consumers that take a bounded number of
+    /// events (e.g. `cache.updates().take(n)`). Equivalent to `watch`.
+    pub fn updates(&self) -> Watch {
+        self.watch()
+    }
+
+    fn subscribe_watcher(&self, snapshot: bool) -> Watch {
+        let (sender, events) = mpsc::unbounded();
+        if let Ok(mut state) = self.inner.lock() {
+            if snapshot {
+                for (key, value) in state.live_snapshot(Instant::now()) {
+                    let _ = sender.unbounded_send(ChangeEvent {
+                        key,
+                        old: None,
+                        new: value,
+                    });
+                }
+            }
+            // Every shard sends its own changes.
+            for shard in state.iter_mut() {
+                shard.watchers.push(sender.clone());
+            }
+        }
+        Watch {
+            events,
+            inner: Arc::downgrade(&self.inner),
+        }
+    }
+
+    pub fn update_in_background(&self, api: impl Api + 'static) {
+        let initial_fetch = !self.inner.config.skip_initial_fetch;
+        self.spawn_worker(api, None, initial_fetch, Vec::new());
+    }
+
+    /// Merges another `Api` into this cache, tagging the entries it wins
+    /// with `id` (see `source_of`).
+    pub fn add_source(&self, id: impl Into<SourceId>, api: impl Api) {
+        let initial_fetch = !self.inner.config.skip_initial_fetch;
+        self.spawn_worker(api, Some(id.into()), initial_fetch, Vec::new());
+    }
+
+    /// Switches the cache from the `Api` it was built with to `api`, e.g.
+    /// after an endpoint rotates, keeping every cached entry. The old worker
+    /// stops reading its stream, abandons any fetch still in flight, applies
+    /// the updates it already queued and exits before the new one fetches
+    /// and subscribes. Sources attached with `add_source` keep running.
+    /// Returns the cache with `api` as its `api()`.
+    pub fn set_api<B: Api>(self, api: B) -> StreamCache<B> {
+        let replaced = match self.inner.config.poison_policy.lock(&self.inner.sources) {
+            Ok(mut sources) => {
+                let (replaced, kept) = mem::take(&mut *sources)
+                    .into_iter()
+                    .partition(|source| source.id.is_none());
+                *sources = kept;
+                replaced
+            }
+            Err(_) => Vec::new(),
+        };
+        let stops = replaced
+            .into_iter()
+            .map(|source: Source| {
+                source.stop.send_replace(true);
+                source.stop
+            })
+            .collect();
+        let initial_fetch = !self.inner.config.skip_initial_fetch;
+        self.spawn_worker(api.clone(), None, initial_fetch, stops);
+        StreamCache {
+            inner: self.inner,
+            api,
+        }
+    }
+
+    /// Runs `fetch` on every attached source right away and applies the
+    /// results with the usual fetch semantics.
+    pub async fn fetch_now(&self) -> Result<(), CacheError> {
+        self.refresh_diff().await.map(drop)
+    }
+
+    /// Like `fetch_now`, but returns the entries the fetch inserted or
+    /// changed, with their old and new values.
+    pub async fn refresh_diff(&self) -> Result<Vec<ChangeEvent>, CacheError> {
+        let sources = self
+            .inner
+            .config
+            .poison_policy
+            .lock(&self.inner.sources)?
+            .clone();
+        let mut changes = Vec::new();
+        for source in sources {
+            let fetched = (source.fetch)().await.map_err(CacheError::Fetch)?;
+            changes.extend(self.inner.apply_fetch(fetched, source.id.as_ref()).await?);
+        }
+        Ok(changes)
+    }
+
+    /// Fetches from every source but only adds the keys the cache lacks,
+    /// never touching live values, e.g. after starting with
+    /// `skip_initial_fetch`. Returns how many keys were added.
+    pub async fn backfill(&self) -> Result<usize, CacheError> {
+        let sources = self
+            .inner
+            .config
+            .poison_policy
+            .lock(&self.inner.sources)?
+            .clone();
+        let mut added = 0;
+        for source in sources {
+            let fetched = (source.fetch)().await.map_err(CacheError::Fetch)?;
+            let fetched = self.inner.merge_fetched(fetched);
+            let mut shards = self.inner.lock()?;
+            let now = Instant::now();
+            for (city, temperature, ttl) in fetched {
+                let state = shards.of(&city);
+                if state.get_live(&city, now).is_some() {
+                    continue;
+                }
+                match self.inner.apply(
+                    state,
+                    city,
+                    temperature,
+                    ttl,
+                    source.id.as_ref(),
+                    Origin::Fetch,
+                ) {
+                    Ok(InsertOutcome::Inserted | InsertOutcome::Evicted { .. }) => added += 1,
+                    Ok(_) => {}
+                    Err(err) => state.errors.push(err),
+                }
+            }
+        }
+        Ok(added)
+    }
+
+    /// Reports, per attached source, whether its fetch and stream have
+    /// delivered and the latest error it hit.
+    pub fn source_status(&self) -> Vec<SourceStatus> {
+        let Ok(sources) = self.inner.config.poison_policy.lock(&self.inner.sources) else {
+            return Vec::new();
+        };
+        sources
+            .iter()
+            .map(|source| {
+                source
+                    .status
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .clone()
+            })
+            .collect()
+    }
+
+    /// Starts a worker for `api` once the workers behind `after` have
+    /// exited.
+    fn spawn_worker(
+        &self,
+        api: impl Api,
+        source: Option<SourceId>,
+        initial_fetch: bool,
+        after: Vec<Arc<watch::Sender<bool>>>,
+    ) {
+        if self.inner.config.serialize_api_calls {
+            let api = SerializedApi {
+                api,
+                lock: Arc::default(),
+            };
+            self.start_worker(api, source, initial_fetch, after);
+        } else {
+            self.start_worker(api, source, initial_fetch, after);
+        }
+    }
+
+    /// `spawn_worker` with the `Api` to run, which is wrapped under
+    /// `serialize_api_calls`.
+    fn start_worker(
+        &self,
+        api: impl Api,
+        source: Option<SourceId>,
+        initial_fetch: bool,
+        after: Vec<Arc<watch::Sender<bool>>>,
+    ) {
+        let inner = Arc::clone(&self.inner);
+        let snapshot = inner.config.snapshot_from_subscribe;
+        let initial_fetch = initial_fetch && !snapshot;
+        if initial_fetch || snapshot {
+            inner.pending_baselines.send_modify(|pending| *pending += 1);
+        }
+        let status = Arc::new(Mutex::new(SourceStatus {
+            id: source.clone(),
+            ..SourceStatus::default()
+        }));
+        let (stop, mut stopped) = watch::channel(false);
+        if let Ok(mut sources) = inner.config.poison_policy.lock(&inner.sources) {
+            let fetch_api = api.clone();
+            sources.push(Source {
+                id: source.clone(),
+                fetch: Arc::new(move || {
+                    let api = fetch_api.clone();
+                    async move { api.fetch_updates().await }.boxed()
+                }),
+                status: Arc::clone(&status),
+                stop: Arc::new(stop),
+            });
+        }
+
+        let mut shutdown = inner.shutdown.subscribe();
+        inner.running_workers.fetch_add(1, AtomicOrdering::SeqCst);
+        let worker_inner = Arc::clone(&inner);
+        let worker = tokio::spawn(async move {
+            let inner = worker_inner;
+            for replaced in after {
+                replaced.closed().await;
+            }
+            // Start subscribing to updates
+            let subscribe_span = inner.span("subscribe", None);
+            let mut subscription = inner.subscribe(&api).await;
+
+            // Under `WritePriority::FetchFirst` the stream waits for the
+            // initial fetch. A dropped sender releases the waiter.
+            let priority = inner.config.write_priority;
+            let (fetch_applied, mut fetch_applied_rx) = watch::channel(false);
+
+            // Spawn a task to handle the fetch operation
+            let fetch_inner = Arc::clone(&inner);
+            let fetch_source = source.clone();
+            let fetch_api = api.clone();
+            let fetch_status = Arc::clone(&status);
+            let fetch_handle = tokio::spawn(async move {
+                if initial_fetch {
+                    let (retries, backoff) = fetch_inner.config.initial_fetch_retries;
+                    let mut backoff = Backoff::new(backoff);
+                    for attempt in 0..=retries {
+                        let fetched = fetch_inner
+                            .fetch_from(&fetch_api, fetch_source.as_ref(), &fetch_status)
+                            .await;
+                        if fetched || attempt == retries {
+                            break;
+                        }
+                        time::sleep(backoff.step()).await;
+                    }
+                    fetch_applied.send_replace(true);
+                    fetch_inner.baseline_done();
+                }
+            });
+
+            // A single applier drains the channel strictly in receive order,
+            // so updates to the same key are never reordered, while a full
+            // channel pushes back on the stream. With several shards it
+            // hands each update on to its shard's applier instead.
+            let (sender, mut receiver) = tokio::sync::mpsc::channel(inner.config.update_buffer);
+            let apply_inner = Arc::clone(&inner);
+            let resync_api = api.clone();
+            let apply_status = Arc::clone(&status);
+            let mut apply_stopped = stopped.clone();
+            let apply_span = subscribe_span.clone();
+            let apply_handle = tokio::spawn(async move {
+                if initial_fetch && priority == WritePriority::FetchFirst {
+                    let _ = fetch_applied_rx.wait_for(|applied| *applied).await;
+                }
+                let failed = |err: CacheError| {
+                    record_status(&apply_status, |status| {
+                        status.last_error = Some(err.clone())
+                    });
+                    apply_inner.report_error(err.clone());
+                    err
+                };
+                let mut in_snapshot = snapshot;
+                let origin = |in_snapshot: bool| {
+                    if in_snapshot {
+                        Origin::Fetch
+                    } else {
+                        Origin::Stream
+                    }
+                };
+                // Applies one update, returning whether it asks for a resync.
+                let apply =
+                    |state: &mut Shards, update: Update, in_snapshot: &mut bool| match update {
+                        Update::SnapshotComplete => {
+                            if mem::take(in_snapshot) {
+                                apply_inner.baseline_done();
+                            }
+                            false
+                        }
+                        update => {
+                            let _span = apply_inner.span("apply", apply_span.as_ref());
+                            let resync = update == Update::Resync;
+                            let origin = origin(*in_snapshot);
+                            apply_inner.apply_update(state, update, source.as_ref(), origin);
+                            resync
+                        }
+                    };
+                let mut appliers = ShardAppliers::spawn(
+                    &apply_inner,
+                    source.clone(),
+                    &apply_status,
+                    apply_span.clone(),
+                );
+                let resolver = apply_inner.stream_resolver();
+                let middleware = !apply_inner.config.middleware.is_empty();
+                while let Some(update) = receiver.recv().await {
+                    let prepared = apply_inner
+                        .prepare_streamed(
+                            update,
+                            source.as_ref(),
+                            resolver.as_ref(),
+                            apply_span.as_ref(),
+                        )
+                        .await;
+                    let update = match prepared {
+                        Ok(Prepared::Pending(update)) => update,
+                        Ok(Prepared::Vetoed) => continue,
+                        Ok(Prepared::Applied) => continue,
+                        Err(err) => return Err(failed(err)),
+                    };
+                    let resync = match &mut appliers {
+                        Some(appliers)
+                            if !matches!(update, Update::Resync | Update::SnapshotComplete) =>
+                        {
+                            let origin = origin(in_snapshot);
+                            for (shard, update) in apply_inner.split_by_shard(update) {
+                                if !appliers.send(shard, ShardWork::Apply(update, origin)).await {
+                                    return appliers.finish().await;
+                                }
+                            }
+                            false
+                        }
+                        appliers => {
+                            // A resync or the end of a snapshot covers every
+                            // shard, so what was handed out before it goes
+                            // in first.
+                            if let Some(appliers) = appliers {
+                                if !appliers.flush().await {
+                                    return appliers.finish().await;
+                                }
+                            }
+                            let mut state = match apply_inner.lock() {
+                                Ok(state) => state,
+                                Err(err) => return Err(failed(err)),
+                            };
+                            let mut resync = apply(&mut state, update, &mut in_snapshot);
+                            // Anything else already queued goes in under the
+                            // same lock, which keeps a fast stream from being
+                            // bound by lock handoffs.
+                            // A resync ends the batch, so the updates after it
+                            // wait for the fetch.
+                            // With middleware there is no batch: each update
+                            // has to pass through it first. The shard
+                            // appliers batch on their own.
+                            for _ in 1..APPLY_BATCH {
+                                if resync || middleware || appliers.is_some() {
+                                    break;
+                                }
+                                let Ok(update) = receiver.try_recv() else {
+                                    break;
+                                };
+                                resync = apply(&mut state, update, &mut in_snapshot);
+                            }
+                            resync
+                        }
+                    };
+                    // The updates streamed after a resync land on top of the
+                    // fetch. A stopped worker only drains its queue, so it
+                    // gives up on the fetch.
+                    if resync {
+                        tokio::select! {
+                            biased;
+                            _ = apply_stopped.wait_for(|stopped| *stopped) => {}
+                            _ = apply_inner.fetch_from(&resync_api, source.as_ref(), &apply_status) => {}
+                        }
+                    }
+                }
+                if let Some(appliers) = &mut appliers {
+                    appliers.finish().await?;
+                }
+                // A stream that ends early will not complete its snapshot.
+                if in_snapshot {
+                    apply_inner.baseline_done();
+                }
+                Ok(())
+            });
+
+            // Process subscription updates until the stream ends or a
+            // graceful shutdown starts
+            let mut outcome = Ok(());
+            let mut streamed = false;
+            let mut resubscribe = Backoff::new(match inner.config.initial_fetch_retries.1 {
+                Duration::ZERO => RESUBSCRIBE_BACKOFF,
+                backoff => backoff,
+            });
+            loop {
+                let next = async {
+                    match inner.config.idle_timeout {
+                        Some(idle) => time::timeout(idle, subscription.next())
+                            .await
+                            .map_err(|_| CacheError::Idle(idle)),
+                        None => Ok(subscription.next().await),
+                    }
+                };
+                let update = tokio::select! {
+                    biased;
+                    _ = shutdown.wait_for(|stopped| *stopped) => break,
+                    _ = stopped.wait_for(|stopped| *stopped) => break,
+                    update = next => update,
+                };
+                let update = match update {
+                    Ok(Some(Ok(update))) => Ok(update),
+                    Ok(Some(Err(err))) => Err(err),
+                    Ok(None) => break,
+                    Err(err) => Err(err),
+                };
+                let update = match update {
+                    Ok(update) => {
+                        resubscribe.reset();
+                        if !mem::replace(&mut streamed, true) {
+                            record_status(&status, |status| status.streamed = true);
+                        }
+                        update
+                    }
+                    Err(err) => {
+                        record_status(&status, |status| status.last_error = Some(err.clone()));
+                        inner.report_error(err.clone());
+                        match inner.config.subscription_error_policy.for_error(&err) {
+                            SubscriptionErrorPolicy::Skip => continue,
+                            SubscriptionErrorPolicy::Reconnect => {
+                                // Back off so an upstream that keeps failing
+                                // is not resubscribed in a hot loop.
+                                tokio::select! {
+                                    biased;
+                                    _ = shutdown.wait_for(|stopped| *stopped) => break,
+                                    _ = stopped.wait_for(|stopped| *stopped) => break,
+                                    _ = time::sleep(resubscribe.step()) => {}
+                                }
+                                subscription = inner.subscribe(&api).await;
+                                continue;
+                            }
+                            SubscriptionErrorPolicy::Fail => {
+                                outcome = Err(err);
+                                break;
+                            }
+                        }
+                    }
+                };
+                if sender.send(update).await.is_err() {
+                    break;
+                }
+            }
+            drop(sender);
+
+            // A worker replaced by `set_api` abandons its initial fetch, which
+            // may never resolve, so the new worker is not held up by it.
+            if *stopped.borrow() {
+                fetch_handle.abort();
+            }
+
+            // Ensure the queued updates and the fetch operation complete
+            let applied = join_task(apply_handle).await;
+            match fetch_handle.await {
+                Err(err) if err.is_cancelled() => {
+                    if initial_fetch {
+                        inner.baseline_done();
+                    }
+                }
+                Err(err) => panic::resume_unwind(err.into_panic()),
+                Ok(()) => {}
+            }
+            if let Some(span) = subscribe_span {
+                span.end();
+            }
+            inner.worker_exited();
+            outcome.and(applied)
+        });
+        let inner = &self.inner;
+        if let Ok(mut workers) = inner.config.poison_policy.lock(&inner.workers) {
+            workers.push(worker);
+        }
+    }
+}
+
+/// Registers many caches so their workers can be counted, capped and shut
+/// down together.
+#[derive(Default)]
+pub struct CacheGroup {
+    caches: Mutex<Vec<Weak<Inner>>>,
+    limit: Option<usize>,
+    closed: AtomicBool,
+}
+
+impl CacheGroup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refuses new caches with `CacheError::GroupFull` while `limit` caches
+    /// are alive.
+    pub fn with_limit(limit: usize) -> Self {
+        CacheGroup {
+            limit: Some(limit),
+            ..Self::default()
+        }
+    }
+
+    /// Builds a cache for `api` in this group. Once the group is shut down,
+    /// new caches keep their seeded contents but never read their stream.
+    pub fn new_cache<A: Api>(&self, api: A) -> Result<StreamCache<A>, CacheError> {
+        self.new_cache_with(StreamCache::builder(), api)
+    }
+
+    /// Like `new_cache`, with the options set on `builder`.
+    pub fn new_cache_with<A: Api>(
+        &self,
+        builder: StreamCacheBuilder,
+        api: A,
+    ) -> Result<StreamCache<A>, CacheError> {
+        let mut caches = self.caches.lock().unwrap_or_else(|e| e.into_inner());
+        caches.retain(|cache| cache.strong_count() > 0);
+        if self.limit.is_some_and(|limit| caches.len() >= limit) {
+            return Err(CacheError::GroupFull);
+        }
+        let cache = builder.build_empty();
+        if self.closed.load(AtomicOrdering::SeqCst) {
+            cache.inner.shutdown.send_replace(true);
+        }
+        cache.update_in_background(api.clone());
+        caches.push(Arc::downgrade(&cache.inner));
+        Ok(StreamCache {
+            inner: cache.inner,
+            api,
+        })
+    }
+
+    /// The number of workers still running across the group.
+    pub fn task_count(&self) -> usize {
+        let caches = self.caches.lock().unwrap_or_else(|e| e.into_inner());
+        caches
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|inner| {
+                let workers = inner.workers.lock().unwrap_or_else(|e| e.into_inner());
+                workers
+                    .iter()
+                    .filter(|worker| !worker.is_finished())
+                    .count()
+            })
+            .sum()
+    }
+
+    /// Runs `shutdown_graceful` on every cache in the group.
+    pub async fn shutdown(&self) {
+        self.closed.store(true, AtomicOrdering::SeqCst);
+        let caches: Vec<_> = {
+            let caches = self.caches.lock().unwrap_or_else(|e| e.into_inner());
+            caches.iter().filter_map(Weak::upgrade).collect()
+        };
+        for inner in caches {
+            StreamCache { inner, api: () }.shutdown_graceful().await;
+        }
+    }
+}
+
+/// `Api` for `LocalStreamCache`, without the `Send` bounds.
+#[async_trait(?Send)]
+pub trait LocalApi: 'static + Clone {
+    async fn fetch(&self) -> Result<HashMap<City, Temperature>, String>;
+    async fn subscribe(&self) -> LocalBoxStream<'static, Result<(City, Temperature), String>>;
+}
+
+/// A cache for single-threaded runtimes, kept in `Rc`s and `RefCell`s to
+/// avoid atomics and locking. Its workers run with `spawn_local`, so it must
+/// be created inside a `tokio::task::LocalSet`.
+///
+/// It shares `StreamCache`'s entry store, so TTLs, fetch semantics (fetched
+/// values never overwrite streamed ones), `get`, `remove`, `len`,
+/// `is_empty`, `snapshot`, `watch`, `last_error`, `source_status`,
+/// `fetch_now`, `shutdown_graceful` and `update_in_background` behave the
+/// same, and `insert` stores the value with the latest write winning,
+/// returning the previous value rather than a `Result`. That is its whole
+/// surface. It does not offer:
+///
+/// - constructors other than `new` and `builder`: `from_stream`,
+///   `new_manual`, `with_initial`, `with_idle_timeout`, `try_new`;
+/// - reads: `try_get`, `peek`, `get_as`, `get_or_fallback`,
+///   `get_with_age`, `with_read`, `filter`, `range`, `recent`, `sample`,
+///   `source_of`, `checkpoint`, `to_csv`, `to_csv_with_header`,
+///   `shared_store`, `api`, `ttl`, `capacity`;
+/// - writes: `insert_with_outcome`, `increment`, `extend`, `replace_all`,
+///   `transaction`, `with_entry`, `expire_at`, `pin`, `unpin`,
+///   `shrink_to_fit`;
+/// - change tracking: `watch_with_snapshot`, `watch_batched`,
+///   `watcher_count`, `updates`, `take_dirty`, `wait_for`,
+///   `wait_for_timeout`, `wait_empty`, `wait_ready`, `join`;
+/// - metrics: `metrics`, `reset_metrics`, `windowed_hit_ratio`,
+///   `update_count`, `hot_keys`, `recent_errors`, `aggregate`;
+/// - sources: `add_source`, `set_api`, `refresh_diff`, `backfill`.
+///
+/// `LocalApi` has only `fetch` and `subscribe`, so there are no paginated
+/// fetches or `Update` variants, and with no sweeper expired entries are
+/// dropped when read, without an `on_expire` call.
+#[derive(Clone)]
+pub struct LocalStreamCache {
+    inner: Rc<LocalInner>,
+}
+
+struct LocalInner {
+    state: RefCell<State>,
+    ttl: Option<Duration>,
+    skip_initial_fetch: bool,
+    subscription_error_policy: SubscriptionErrorPolicy,
+    on_error: Option<ErrorHandler>,
+    sources: RefCell<Vec<LocalSource>>,
+    workers: RefCell<Vec<tokio::task::JoinHandle<()>>>,
+    shutdown: watch::Sender<bool>,
+}
+
+/// One `LocalApi` attached to a `LocalStreamCache`.
+struct LocalSource {
+    fetch: LocalFetcher,
+    status: Rc<RefCell<SourceStatus>>,
+}
+
+type LocalFetcher =
+    Rc<dyn Fn() -> LocalBoxFuture<'static, Result<HashMap<City, Temperature>, String>>>;
+
+impl LocalInner {
+    /// Runs `f` on the state. No `on_expire` callback runs here, so entries
+    /// dropped for having expired are discarded, not reported.
+    fn with_state<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        let mut state = self.state.borrow_mut();
+        let result = f(&mut state);
+        state.expired.clear();
+        result
+    }
+
+    fn entry_meta(&self, now: Instant) -> EntryMeta {
+        EntryMeta {
+            source: None,
+            origin: Origin::Stream,
+            expires_at: self.ttl.map(|ttl| now + ttl),
+            ttl: self.ttl,
+            updated_at: now,
+            last_used: 0,
+            written: 0,
+            updates: 0,
+        }
+    }
+
+    /// Writes a streamed or inserted value, returning the previous live one.
+    fn write(&self, key: String, value: u64) -> Option<u64> {
+        let now = Instant::now();
+        self.with_state(|state| {
+            let old = state.get_live(&key, now);
+            state.insert(key, value, self.entry_meta(now));
+            old
+        })
+    }
+
+    /// Applies a fetch result, filling only the keys not yet written.
+    fn apply_fetch(&self, fetched: HashMap<City, Temperature>) {
+        let now = Instant::now();
+        self.with_state(|state| {
+            for (city, temperature) in fetched {
+                if state.get_live(&city, now).is_none() {
+                    state.insert(city, temperature, self.entry_meta(now));
+                }
+            }
+        });
+    }
+
+    fn report_error(&self, error: CacheError) {
+        self.with_state(|state| {
+            state.metrics.errors += 1;
+            state.last_error = Some(error.clone());
+        });
+        // The handler may read the cache, so it runs with the state released.
+        if let Some(on_error) = &self.on_error {
+            on_error(&error, 1);
+        }
+    }
+
+    /// Fetches through `fetch` and applies the result, recording the outcome
+    /// in `status`.
+    async fn fetch_from(
+        &self,
+        fetch: &LocalFetcher,
+        status: &RefCell<SourceStatus>,
+    ) -> Result<(), CacheError> {
+        match fetch().await {
+            Ok(fetched) => {
+                self.apply_fetch(fetched);
+                status.borrow_mut().fetched = true;
+                Ok(())
+            }
+            Err(err) => {
+                let err = CacheError::Fetch(err);
+                status.borrow_mut().last_error = Some(err.clone());
+                self.report_error(err.clone());
+                Err(err)
+            }
+        }
+    }
+}
+
+/// A stream of changes to a `LocalStreamCache`; see `LocalStreamCache::watch`.
+pub struct LocalWatch {
+    events: UnboundedReceiver<ChangeEvent>,
+}
+
+impl Stream for LocalWatch {
+    type Item = ChangeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ChangeEvent>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+/// Configures a `LocalStreamCache`. Each option behaves as its
+/// `StreamCacheBuilder` namesake. Only `ttl`, `skip_initial_fetch`,
+/// `subscription_error_policy`, `on_error` and `initial` are offered; every
+/// other `StreamCacheBuilder` option is missing: `poison_policy`,
+/// `conflict_policy`, `hit_ratio_window`, `measure_lock_contention`,
+/// `track_dirty`, `initial_fetch_retries`, `interest`, `write_priority`,
+/// `with_middleware`, `with_otel_context`, `serialize_api_calls`,
+/// `snapshot_from_subscribe`, `idle_timeout`, `empty_warn_after`,
+/// `with_key_normalizer`, `store`, `stale_while_revalidate`,
+/// `with_aggregate`, `sentinel`, `with_decay`, `sliding_ttl`,
+/// `refresh_ttl_on_equal`, `capacity`, `pins_count_toward_capacity`,
+/// `on_expire`, `with_write_deadline`, `with_value_bounds`, `fallback`,
+/// `with_flap_suppression`, `with_per_key_throttle`, `error_dedup_window`,
+/// `json_log`, `json_log_writer`, `sweep_interval`, `update_buffer`,
+/// `shards`, `fetch_chunk_size` and `on_shutdown_snapshot`, as well as
+/// `build_manual` and `try_build`.
+#[derive(Default)]
+pub struct LocalStreamCacheBuilder {
+    ttl: Option<Duration>,
+    skip_initial_fetch: bool,
+    subscription_error_policy: SubscriptionErrorPolicy,
+    on_error: Option<ErrorHandler>,
+    initial: HashMap<String, u64>,
+}
+
+impl LocalStreamCacheBuilder {
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn skip_initial_fetch(mut self, skip: bool) -> Self {
+        self.skip_initial_fetch = skip;
+        self
+    }
+
+    pub fn subscription_error_policy(mut self, policy: SubscriptionErrorPolicy) -> Self {
+        self.subscription_error_policy = policy;
+        self
+    }
+
+    pub fn on_error(mut self, on_error: ErrorHandler) -> Self {
+        self.on_error = Some(on_error);
+        self
+    }
+
+    pub fn initial(mut self, initial: HashMap<String, u64>) -> Self {
+        self.initial = initial;
+        self
+    }
+
+    pub fn build_empty(self) -> LocalStreamCache {
+        let cache = LocalStreamCache {
+            inner: Rc::new(LocalInner {
+                state: RefCell::default(),
+                ttl: self.ttl,
+                skip_initial_fetch: self.skip_initial_fetch,
+                subscription_error_policy: self.subscription_error_policy,
+                on_error: self.on_error,
+                sources: RefCell::default(),
+                workers: RefCell::default(),
+                shutdown: watch::channel(false).0,
+            }),
+        };
+        for (key, value) in self.initial {
+            cache.inner.write(key, value);
+        }
+        cache
+    }
+
+    pub fn build(self, api: impl LocalApi) -> LocalStreamCache {
+        let cache = self.build_empty();
+        cache.update_in_background(api);
+        cache
+    }
+}
+
+impl Default for LocalStreamCache {
+    fn default() -> Self {
+        Self::builder().build_empty()
+    }
+}
+
+impl LocalStreamCache {
+    pub fn new(api: impl LocalApi) -> Self {
+        Self::builder().build(api)
+    }
+
+    pub fn builder() -> LocalStreamCacheBuilder {
+        LocalStreamCacheBuilder::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<u64> {
+        let now = Instant::now();
+        self.inner.with_state(|state| state.read(key, now))
+    }
+
+    /// Writes `value` as if it had been streamed. Returns the previous value.
+    pub fn insert(&self, key: impl Into<String>, value: u64) -> Option<u64> {
+        self.inner.write(key.into(), value)
+    }
+
+    pub fn remove(&self, key: &str) -> Option<u64> {
+        self.inner.with_state(|state| {
+            let meta = state.meta.remove(key)?;
+            let value = state.values.remove(key)?;
+            (!meta.is_expired(Instant::now())).then_some(value)
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        let now = Instant::now();
+        self.inner.with_state(|state| {
+            state
+                .meta
+                .values()
+                .filter(|meta| !meta.is_expired(now))
+                .count()
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, u64> {
+        let now = Instant::now();
+        self.inner.with_state(|state| state.live_snapshot(now))
+    }
+
+    /// Streams every change to a value, as `StreamCache::watch` does.
+    pub fn watch(&self) -> LocalWatch {
+        let (sender, events) = mpsc::unbounded();
+        self.inner.with_state(|state| state.watchers.push(sender));
+        LocalWatch { events }
+    }
+
+    /// The latest error reported by a worker or `fetch_now`.
+    pub fn last_error(&self) -> Option<CacheError> {
+        self.inner.with_state(|state| state.last_error.clone())
+    }
+
+    /// The health of each attached `LocalApi`, in the order they were added.
+    pub fn source_status(&self) -> Vec<SourceStatus> {
+        let sources = self.inner.sources.borrow();
+        sources
+            .iter()
+            .map(|source| source.status.borrow().clone())
+            .collect()
+    }
+
+    /// Runs `fetch` on every attached source right away and applies the
+    /// results with the usual fetch semantics.
+    pub async fn fetch_now(&self) -> Result<(), CacheError> {
+        let sources: Vec<_> = self
+            .inner
+            .sources
+            .borrow()
+            .iter()
+            .map(|source| (Rc::clone(&source.fetch), Rc::clone(&source.status)))
+            .collect();
+        for (fetch, status) in sources {
+            self.inner.fetch_from(&fetch, &status).await?;
+        }
+        Ok(())
+    }
+
+    /// Stops every worker from reading its stream, then waits until they
+    /// have exited.
+    pub async fn shutdown_graceful(&self) {
+        self.inner.shutdown.send_replace(true);
+        let workers = mem::take(&mut *self.inner.workers.borrow_mut());
+        for worker in workers {
+            let _ = worker.await;
+        }
+    }
+
+    pub fn update_in_background(&self, api: impl LocalApi) {
+        let status = Rc::new(RefCell::new(SourceStatus::default()));
+        let fetch_api = api.clone();
+        let fetch: LocalFetcher = Rc::new(move || {
+            let api = fetch_api.clone();
+            async move { api.fetch().await }.boxed_local()
+        });
+        self.inner.sources.borrow_mut().push(LocalSource {
+            fetch: Rc::clone(&fetch),
+            status: Rc::clone(&status),
+        });
+
+        let inner = Rc::clone(&self.inner);
+        let mut shutdown = inner.shutdown.subscribe();
+        let worker = tokio::task::spawn_local(async move {
+            let fetch = (!inner.skip_initial_fetch).then(|| {
+                let inner = Rc::clone(&inner);
+                let status = Rc::clone(&status);
+                tokio::task::spawn_local(async move {
+                    let _ = inner.fetch_from(&fetch, &status).await;
+                })
+            });
+            let mut subscription = api.subscribe().await;
+            let mut resubscribe = Backoff::new(RESUBSCRIBE_BACKOFF);
+            loop {
+                let item = tokio::select! {
+                    biased;
+                    _ = shutdown.wait_for(|stopped| *stopped) => break,
+                    item = subscription.next() => item,
+                };
+                match item {
+                    Some(Ok((city, temperature))) => {
+                        resubscribe.reset();
+                        status.borrow_mut().streamed = true;
+                        inner.write(city, temperature);
+                    }
+                    Some(Err(err)) => {
+                        let err = CacheError::Subscribe(err);
+                        status.borrow_mut().last_error = Some(err.clone());
+                        inner.report_error(err.clone());
+                        match inner.subscription_error_policy.for_error(&err) {
+                            SubscriptionErrorPolicy::Skip => {}
+                            SubscriptionErrorPolicy::Reconnect => {
+                                tokio::select! {
+                                    biased;
+                                    _ = shutdown.wait_for(|stopped| *stopped) => break,
+                                    _ = time::sleep(resubscribe.step()) => {}
+                                }
+                                subscription = api.subscribe().await;
+                            }
+                            SubscriptionErrorPolicy::Fail => break,
+                        }
+                    }
+                    None => break,
+                }
+            }
+            if let Some(fetch) = fetch {
+                let _ = fetch.await;
+            }
+        });
+        self.inner.workers.borrow_mut().push(worker);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::sync::Notify;
+    use tokio::time;
+
+    use futures::{future, stream::select, FutureExt, StreamExt};
+    use maplit::hashmap;
+
+    use super::*;
+
+    #[derive(Default, Clone)]
+    struct TestApi {
+        signal: Arc<Notify>,
+        fetch_error: Option<String>,
+    }
+
+    #[async_trait]
+    impl Api for TestApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            // Simulate error if fetch_error is set
+            if let Some(err) = self.fetch_error.clone() {
+                return Err(err);
+            }
+            // fetch is slow and may get delayed until after we receive the first updates
+            self.signal.notified().await;
+            Ok(hashmap! {
+                "Berlin".to_string() => 29,
+                "Paris".to_string() => 31,
+            })
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            let results = vec![
+                Ok(("London".to_string(), 27)),
+                Ok(("Paris".to_string(), 32)),
+                // Simulate an error for demonstration purposes
+                Err("Subscription error".to_string()),
+            ];
+            select(
+                futures::stream::iter(results),
+                async {
+                    self.signal.notify_one();
+                    future::pending().await
+                }
+                .into_stream(),
+            )
+            .boxed()
+        }
+    }
+
+    /// Serves a fixed fetch result and streams `updates`, waiting `interval`
+    /// before each one, then ends.
+    #[derive(Default, Clone)]
+    struct MockApi {
+        fetched: HashMap<City, Temperature>,
+        updates: Vec<(City, Temperature)>,
+        interval: Duration,
+        fetch_calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Api for MockApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            self.fetch_calls.fetch_add(1, Ordering::SeqCst);
+            Ok(self.fetched.clone())
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            let interval = self.interval;
+            futures::stream::iter(self.updates.clone())
+                .then(move |update| async move {
+                    if !interval.is_zero() {
+                        time::sleep(interval).await;
+                    }
+                    Ok(update)
+                })
+                .boxed()
+        }
+    }
+
+    /// Fetches and streams `fetched` and `updates` as-is.
+    #[derive(Clone, Default)]
+    struct UpdatesApi {
+        fetched: Vec<Update>,
+        updates: Vec<Update>,
+    }
+
+    #[async_trait]
+    impl Api for UpdatesApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            Err("no snapshot".to_string())
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            futures::stream::empty().boxed()
+        }
+
+        async fn subscribe_updates(&self) -> BoxStream<Result<Update, String>> {
+            futures::stream::iter(self.updates.clone().into_iter().map(Ok)).boxed()
+        }
+
+        async fn fetch_updates(&self) -> Result<Vec<Update>, String> {
+            Ok(self.fetched.clone())
+        }
+    }
+
+    fn lock_count<A>(cache: &StreamCache<A>) -> usize {
+        cache
+            .inner
+            .lock_count
+            .load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// A source that is down: `fetch` fails and the stream ends at once.
+    #[derive(Clone)]
+    struct DeadApi;
+
+    #[async_trait]
+    impl Api for DeadApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            Err("connection refused".to_string())
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            futures::stream::empty().boxed()
+        }
+    }
+
+    /// Streams the same error `errors` times.
+    #[derive(Clone)]
+    struct FailingStreamApi {
+        errors: usize,
+    }
+
+    #[async_trait]
+    impl Api for FailingStreamApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            Ok(HashMap::new())
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            futures::stream::iter(vec![Err("upstream down".to_string()); self.errors]).boxed()
+        }
+    }
+
+    type Reported = Arc<Mutex<Vec<(CacheError, usize)>>>;
+
+    fn error_recorder() -> (ErrorHandler, Reported) {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&reported);
+        let on_error: ErrorHandler = Arc::new(move |err: &CacheError, repeats: usize| {
+            recorder.lock().unwrap().push((err.clone(), repeats));
+        });
+        (on_error, reported)
+    }
+
+    #[tokio::test]
+    async fn works() {
+        let cache = StreamCache::new(TestApi::default());
+
+        // Allow cache to update
+        time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(cache.get("Berlin"), Some(29));
+        assert_eq!(cache.get("London"), Some(27));
+        assert_eq!(cache.get("Paris"), Some(32));
+    }
+
+    #[tokio::test]
+    async fn test_subscription_error_handling() {
+        let api = TestApi::default();
+        let cache = StreamCache::new(api.clone());
+
+        time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(cache.get("London"), Some(27));
+        assert_eq!(cache.get("Paris"), Some(32));
+        time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(cache.get("London"), Some(27));
+    }
+
+    #[tokio::test]
+    async fn test_missing_keys() {
+        let cache = StreamCache::new(TestApi::default());
+        assert_eq!(cache.get("NotExist"), None);
+    }
+
+    #[tokio::test]
+    async fn test_initial_state() {
+        let cache = StreamCache::new(TestApi::default());
+        assert_eq!(cache.get("Berlin"), None);
+        assert_eq!(cache.get("London"), None);
+        assert_eq!(cache.get("Paris"), None);
+    }
+
+    fn poison<A>(cache: &StreamCache<A>) {
+        let inner = Arc::clone(&cache.inner);
+        let _ = std::thread::spawn(move || {
+            let _guard = inner.shards[0].lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "poisoned")]
+    async fn test_poison_policy_panic() {
+        let cache = StreamCache::new(TestApi::default());
+        time::sleep(Duration::from_millis(100)).await;
+
+        poison(&cache);
+        cache.get("London");
+    }
+
+    #[tokio::test]
+    async fn test_poison_policy_recover() {
+        let cache = StreamCache::builder()
+            .poison_policy(PoisonPolicy::Recover)
+            .build(TestApi::default());
+        time::sleep(Duration::from_millis(100)).await;
+
+        poison(&cache);
+        assert_eq!(cache.try_get("London"), Ok(Some(27)));
+        assert_eq!(cache.get("Paris"), Some(32));
+    }
+
+    #[tokio::test]
+    async fn test_poison_policy_error() {
+        let cache = StreamCache::builder()
+            .poison_policy(PoisonPolicy::Error)
+            .build(TestApi::default());
+        time::sleep(Duration::from_millis(100)).await;
+
+        poison(&cache);
+        assert_eq!(cache.try_get("London"), Err(CacheError::Poisoned));
+        assert_eq!(cache.get("London"), None);
+    }
+
+    #[tokio::test]
+    async fn test_watch_with_snapshot() {
+        let cache = StreamCache::new(TestApi::default());
+        time::sleep(Duration::from_millis(100)).await;
+
+        let mut watch = cache.watch_with_snapshot();
+        cache.update_in_background(MockApi {
+            updates: vec![("Rome".to_string(), 25)],
+            ..Default::default()
+        });
+
+        let mut snapshot: Vec<_> = (&mut watch).take(3).collect().await;
+        snapshot.sort_by(|a, b| a.key.cmp(&b.key));
+        let keys: Vec<_> = snapshot.iter().map(|e| (e.key.as_str(), e.new)).collect();
+        assert_eq!(keys, vec![("Berlin", 29), ("London", 27), ("Paris", 32)]);
+        assert!(snapshot.iter().all(|e| e.old.is_none()));
+
+        let update = watch.next().await.unwrap();
+        assert_eq!(
+            update,
+            ChangeEvent {
+                key: "Rome".to_string(),
+                old: None,
+                new: 25
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watch_with_snapshot_skips_expired_entries() {
+        let cache = StreamCache::builder()
+            .ttl(Duration::from_secs(5))
+            .build_empty();
+        cache.insert("Paris", 31).unwrap();
+        time::advance(Duration::from_secs(3)).await;
+        cache.insert("London", 27).unwrap();
+        time::advance(Duration::from_secs(3)).await;
+
+        let watch = cache.watch_with_snapshot();
+        drop(cache);
+        let events: Vec<_> = watch.collect().await;
+        assert_eq!(
+            events,
+            vec![ChangeEvent {
+                key: "London".to_string(),
+                old: None,
+                new: 27,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_source_of_tracks_winning_source() {
+        let cache = StreamCache::builder()
+            .conflict_policy(ConflictPolicy::KeepMax)
+            .build_empty();
+        cache.add_source(
+            "north",
+            MockApi {
+                updates: vec![("Paris".to_string(), 30), ("Oslo".to_string(), 12)],
+                ..Default::default()
+            },
+        );
+        time::sleep(Duration::from_millis(50)).await;
+        cache.add_source(
+            "south",
+            MockApi {
+                updates: vec![("Paris".to_string(), 25), ("Rome".to_string(), 28)],
+                ..Default::default()
+            },
+        );
+        time::sleep(Duration::from_millis(50)).await;
+
+        // "south" lost Paris under KeepMax, so provenance stays with "north".
+        assert_eq!(cache.get("Paris"), Some(30));
+        assert_eq!(cache.source_of("Paris"), Some("north".to_string()));
+        assert_eq!(cache.source_of("Rome"), Some("south".to_string()));
+
+        cache.add_source(
+            "south",
+            MockApi {
+                updates: vec![("Paris".to_string(), 35)],
+                ..Default::default()
+            },
+        );
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get("Paris"), Some(35));
+        assert_eq!(cache.source_of("Paris"), Some("south".to_string()));
+        assert_eq!(cache.source_of("NotExist"), None);
+    }
+
+    #[tokio::test]
+    async fn test_batch_update_applies_under_one_lock() {
+        let cache = StreamCache::builder().build_empty();
+        let before = lock_count(&cache);
+        cache.update_in_background(UpdatesApi {
+            updates: vec![Update::Batch(vec![
+                ("Berlin".to_string(), 29),
+                ("London".to_string(), 27),
+                ("Paris".to_string(), 32),
+            ])],
+            ..Default::default()
+        });
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(lock_count(&cache) - before, 1);
+        assert_eq!(cache.get("Berlin"), Some(29));
+        assert_eq!(cache.get("London"), Some(27));
+        assert_eq!(cache.get("Paris"), Some(32));
+    }
+
+    fn repeated_paris() -> MockApi {
+        MockApi {
+            updates: vec![("Paris".to_string(), 30), ("Paris".to_string(), 30)],
+            interval: Duration::from_secs(6),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refresh_ttl_on_equal_keeps_entry_alive() {
+        let cache = StreamCache::builder()
+            .ttl(Duration::from_secs(10))
+            .refresh_ttl_on_equal(true)
+            .build(repeated_paris());
+
+        // Written at 6s, refreshed at 12s, so still alive at 17s.
+        time::sleep(Duration::from_secs(17)).await;
+        assert_eq!(cache.get("Paris"), Some(30));
+
+        time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(cache.get("Paris"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_duplicate_does_not_refresh_ttl() {
+        let cache = StreamCache::builder()
+            .ttl(Duration::from_secs(10))
+            .refresh_ttl_on_equal(false)
+            .build(repeated_paris());
+
+        // Written at 6s; the duplicate at 12s does not extend it past 16s.
+        time::sleep(Duration::from_secs(15)).await;
+        assert_eq!(cache.get("Paris"), Some(30));
+
+        time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(cache.get("Paris"), None);
+    }
+
+    #[tokio::test]
+    async fn test_updates_take_unregisters_on_drop() {
+        let cache = StreamCache::new(TestApi::default());
+
+        let events: Vec<_> = cache.updates().take(2).collect().await;
+        assert_eq!(events.len(), 2);
+        for event in &events {
+            assert!(cache.get(&event.key).is_some());
+        }
+
+        assert!(cache.inner.lock_shard(0).unwrap().watchers.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_per_item_ttl_overrides_global() {
+        let cache = StreamCache::builder()
+            .ttl(Duration::from_secs(10))
+            .build(UpdatesApi {
+                updates: vec![
+                    Update::Expiring("Paris".to_string(), 30, Duration::from_secs(1)),
+                    Update::Single("London".to_string(), 27),
+                ],
+                ..Default::default()
+            });
+
+        time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(cache.get("Paris"), Some(30));
+        assert_eq!(cache.get("London"), Some(27));
+
+        time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(cache.get("Paris"), None);
+        assert_eq!(cache.get("London"), Some(27));
+
+        time::sleep(Duration::from_secs(9)).await;
+        assert_eq!(cache.get("London"), None);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_fetch_lets_readers_in() {
+        const ENTRIES: usize = 10_000;
+        const CHUNK: usize = 1_000;
+        let fetched = (0..ENTRIES)
+            .map(|i| (format!("city-{i}"), i as u64))
+            .collect();
+        let cache = StreamCache::builder()
+            .fetch_chunk_size(CHUNK)
+            .build(MockApi {
+                fetched,
+                ..Default::default()
+            });
+
+        // On this single-threaded runtime the reader only runs while the
+        // fetch has released the lock, so it sees whole chunks.
+        let mut seen = Vec::new();
+        while cache.len() < ENTRIES {
+            seen.push(cache.len());
+            tokio::task::yield_now().await;
+        }
+        assert!(seen.iter().all(|len| len % CHUNK == 0), "{seen:?}");
+        assert!(
+            seen.iter().any(|len| (1..ENTRIES).contains(len)),
+            "no read between chunks: {seen:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config_accessors() {
+        let cache = StreamCache::builder()
+            .ttl(Duration::from_secs(30))
+            .capacity(100)
+            .build(TestApi::default());
+        assert_eq!(cache.ttl(), Some(Duration::from_secs(30)));
+        assert_eq!(cache.capacity(), Some(100));
+
+        let cache = StreamCache::new(TestApi::default());
+        assert_eq!(cache.ttl(), None);
+        assert_eq!(cache.capacity(), None);
+    }
+
+    #[tokio::test]
+    async fn test_capacity_evicts_least_recently_used() {
+        let cache = StreamCache::builder().capacity(2).build_empty();
+        cache.update_in_background(MockApi {
+            updates: vec![("Berlin".to_string(), 29), ("London".to_string(), 27)],
+            ..Default::default()
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get("Berlin"), Some(29));
+
+        cache.update_in_background(MockApi {
+            updates: vec![("Paris".to_string(), 32)],
+            ..Default::default()
+        });
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("London"), None);
+        assert_eq!(cache.get("Berlin"), Some(29));
+        assert_eq!(cache.get("Paris"), Some(32));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_updates_for_one_key_stay_ordered() {
+        const UPDATES: u64 = 10_000;
+        let cache = StreamCache::builder().update_buffer(16).build_empty();
+        let watch = cache.watch();
+        cache.update_in_background(MockApi {
+            updates: (0..UPDATES).map(|i| ("Paris".to_string(), i)).collect(),
+            ..Default::default()
+        });
+
+        let seen: Vec<_> = watch
+            .take(UPDATES as usize)
+            .map(|event| event.new)
+            .collect()
+            .await;
+        assert!(seen.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(cache.get("Paris"), Some(UPDATES - 1));
+    }
+
+    /// Streams `rounds` rising values for each of `keys` keys, round by
+    /// round, then ends.
+    fn many_keys(keys: u64, rounds: u64) -> MockApi {
+        MockApi {
+            updates: (0..rounds)
+                .flat_map(|round| (0..keys).map(move |key| (format!("City{key}"), round)))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_shards_apply_many_keys() {
+        const KEYS: u64 = 500;
+        const ROUNDS: u64 = 20;
+        for shards in [1, 4] {
+            let cache = StreamCache::builder().shards(shards).build_empty();
+            let watch = cache.watch();
+            cache.update_in_background(many_keys(KEYS, ROUNDS));
+
+            let events: Vec<_> = watch.take((KEYS * ROUNDS) as usize).collect().await;
+            // Each key's updates arrive in order, whichever shard holds it.
+            let mut last = HashMap::new();
+            for event in events {
+                assert_eq!(last.insert(event.key, event.new), event.old);
+            }
+            assert_eq!(last.len(), KEYS as usize);
+            assert_eq!(cache.len(), KEYS as usize);
+            for key in 0..KEYS {
+                assert_eq!(cache.get(&format!("City{key}")), Some(ROUNDS - 1));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shards_resync_clears_every_shard() {
+        let cache = StreamCache::builder().shards(4).build_empty();
+        cache.update_in_background(UpdatesApi {
+            fetched: vec![Update::Single("Berlin".to_string(), 29)],
+            updates: vec![
+                Update::Batch((0..50).map(|i| (format!("City{i}"), i)).collect()),
+                Update::Resync,
+                Update::Single("Paris".to_string(), 32),
+            ],
+        });
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(
+            cache.snapshot(),
+            hashmap! { "Berlin".to_string() => 29, "Paris".to_string() => 32 }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shards_whole_cache_operations() {
+        let cache = StreamCache::builder()
+            .shards(4)
+            .store(BTreeStore::default())
+            .build_empty();
+        cache.extend((0..100).map(|i| (format!("City{i:02}"), i)));
+
+        assert_eq!(cache.len(), 100);
+        assert_eq!(cache.snapshot().len(), 100);
+        assert_eq!(cache.with_read(|store| store.iter().count()), 100);
+        assert_eq!(
+            cache.range("City10".to_string().."City13".to_string()),
+            Ok(vec![
+                ("City10".to_string(), 10),
+                ("City11".to_string(), 11),
+                ("City12".to_string(), 12),
+            ])
+        );
+        {
+            let store = cache.shared_store();
+            let mut map = store.lock().unwrap();
+            assert_eq!(map.len(), 100);
+            map.retain(|_, value| value < 10);
+            map.insert("Rome".to_string(), 30).unwrap();
+        }
+        assert_eq!(cache.len(), 11);
+        assert_eq!(cache.get("Rome"), Some(30));
+        assert_eq!(cache.get("City05"), Some(5));
+
+        cache.replace_all(hashmap! { "Oslo".to_string() => 4 });
+        assert_eq!(cache.snapshot(), hashmap! { "Oslo".to_string() => 4 });
+    }
+
+    #[tokio::test]
+    async fn test_shards_split_capacity() {
+        for (shards, capacity) in [(4, 8), (4, 10), (3, 10), (8, 5)] {
+            let cache = StreamCache::builder()
+                .shards(shards)
+                .capacity(capacity)
+                .build_empty();
+            cache.extend((0..100).map(|i| (format!("City{i}"), i)));
+
+            // The shares add up to the capacity, so the cache never holds
+            // more, and each shard fills its own.
+            assert!(cache.len() <= capacity, "{shards} shards over {capacity}");
+            assert!(cache.get("City99").is_some());
+            assert_eq!(cache.inner.shards.len(), shards.min(capacity));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_skip_initial_fetch_keeps_fetch_now() {
+        let api = MockApi {
+            fetched: hashmap! { "Berlin".to_string() => 29 },
+            updates: vec![("London".to_string(), 27)],
+            ..Default::default()
+        };
+        let cache = StreamCache::builder()
+            .skip_initial_fetch(true)
+            .build(api.clone());
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(api.fetch_calls.load(Ordering::SeqCst), 0);
+        assert_eq!(cache.get("London"), Some(27));
+        assert_eq!(cache.get("Berlin"), None);
+
+        cache.fetch_now().await.unwrap();
+        assert_eq!(api.fetch_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.get("Berlin"), Some(29));
+    }
+
+    #[tokio::test]
+    async fn test_with_entry_increments_in_place() {
+        let cache = StreamCache::new(TestApi::default());
+        time::sleep(Duration::from_millis(100)).await;
+
+        let doubled = cache.with_entry("Paris", |value| {
+            let value = value.unwrap();
+            *value += 1;
+            *value * 2
+        });
+        assert_eq!(doubled, 66);
+        assert_eq!(cache.get("Paris"), Some(33));
+
+        assert!(cache.with_entry("NotExist", |value| value.is_none()));
+    }
+
+    type Recorded = Arc<Mutex<Vec<(String, u64)>>>;
+
+    fn expiry_recorder() -> (ExpireCallback, Recorded) {
+        let expired = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&expired);
+        let on_expire: ExpireCallback = Arc::new(move |key: &str, value: u64| {
+            recorder.lock().unwrap().push((key.to_string(), value));
+        });
+        (on_expire, expired)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_on_expire_fires_on_lazy_read() {
+        let (on_expire, expired) = expiry_recorder();
+        let cache = StreamCache::builder()
+            .ttl(Duration::from_secs(1))
+            .on_expire(on_expire)
+            .build(MockApi {
+                updates: vec![("Paris".to_string(), 30)],
+                ..Default::default()
+            });
+
+        time::sleep(Duration::from_secs(2)).await;
+        assert!(expired.lock().unwrap().is_empty());
+
+        assert_eq!(cache.get("Paris"), None);
+        assert_eq!(*expired.lock().unwrap(), vec![("Paris".to_string(), 30)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_on_expire_fires_from_sweeper() {
+        let (on_expire, expired) = expiry_recorder();
+        let cache = StreamCache::builder()
+            .ttl(Duration::from_secs(1))
+            .sweep_interval(Duration::from_millis(500))
+            .on_expire(on_expire)
+            .build(MockApi {
+                updates: vec![("Paris".to_string(), 30)],
+                ..Default::default()
+            });
+
+        time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(*expired.lock().unwrap(), vec![("Paris".to_string(), 30)]);
+        assert!(cache.inner.lock_shard(0).unwrap().values.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_insert_outcomes() {
+        let cache = StreamCache::builder()
+            .conflict_policy(ConflictPolicy::KeepMax)
+            .capacity(2)
+            .build_empty();
+
+        assert_eq!(
+            cache.insert_with_outcome("Paris", 30),
+            Ok(InsertOutcome::Inserted)
+        );
+        assert_eq!(
+            cache.insert_with_outcome("Paris", 32),
+            Ok(InsertOutcome::Updated { old: 30 })
+        );
+        assert_eq!(
+            cache.insert_with_outcome("Paris", 25),
+            Ok(InsertOutcome::RejectedByPolicy { kept: 32 })
+        );
+        assert_eq!(cache.insert("London", 27), Ok(None));
+        assert_eq!(
+            cache.insert_with_outcome("Berlin", 29),
+            Ok(InsertOutcome::Evicted {
+                key: "Paris".to_string()
+            })
+        );
+        assert_eq!(cache.get("Paris"), None);
+        assert_eq!(cache.insert("Berlin", 31), Ok(Some(29)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_many_sources_many_keys() {
+        const SOURCES: usize = 8;
+        const KEYS: usize = 1_000;
+        let cache = StreamCache::builder().build_empty();
+        for source in 0..SOURCES {
+            cache.add_source(
+                format!("source-{source}"),
+                MockApi {
+                    updates: (0..KEYS)
+                        .map(|key| (format!("{source}-{key}"), (source * KEYS + key) as u64))
+                        .collect(),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let started = std::time::Instant::now();
+        while cache.len() < SOURCES * KEYS {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "updates stalled"
+            );
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        for source in 0..SOURCES {
+            for key in 0..KEYS {
+                assert_eq!(
+                    cache.get(&format!("{source}-{key}")),
+                    Some((source * KEYS + key) as u64)
+                );
+            }
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_log_lines() {
+        let buf = SharedBuf::default();
+        let cache = StreamCache::builder()
+            .json_log_writer(buf.clone())
+            .build_empty();
+        cache.add_source(
+            "test",
+            MockApi {
+                fetched: hashmap! { "Berlin".to_string() => 29 },
+                updates: vec![("London".to_string(), 27), ("Paris".to_string(), 32)],
+                ..Default::default()
+            },
+        );
+        time::sleep(Duration::from_millis(100)).await;
+
+        let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        for (i, line) in lines.iter().enumerate() {
+            assert_eq!(line["seq"], i as u64 + 1);
+            assert_eq!(line["source"], "test");
+            assert!(line["timestamp"].as_u64().unwrap() > 0);
+            let key = line["key"].as_str().unwrap();
+            assert_eq!(line["new"], cache.get(key).unwrap());
+            assert!(line["old"].is_null());
+        }
+        assert_eq!(super::json_string("a\"b\n"), r#""a\"b\n""#);
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_keeps_old_values() {
+        let cache = StreamCache::new(TestApi::default());
+        time::sleep(Duration::from_millis(100)).await;
+
+        let checkpoint = cache.checkpoint();
+        cache.insert("Paris", 40).unwrap();
+        cache.insert("Rome", 25).unwrap();
+
+        assert_eq!(cache.get("Paris"), Some(40));
+        assert_eq!(checkpoint.get("Paris"), Some(32));
+        assert_eq!(checkpoint.get("Rome"), None);
+        assert_eq!(checkpoint.clone().len(), 3);
+        assert_eq!(cache.snapshot().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_flap_suppression_holds_alternating_values() {
+        let cache = StreamCache::builder()
+            .with_flap_suppression(Duration::from_secs(3600), 3)
+            .build_empty();
+        let watch = cache.watch();
+
+        let mut updates = Vec::new();
+        for _ in 0..3 {
+            updates.push(("Paris".to_string(), 30));
+            updates.push(("Paris".to_string(), 31));
+        }
+        cache.update_in_background(MockApi {
+            updates,
+            ..Default::default()
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get("Paris"), Some(30));
+
+        cache.update_in_background(MockApi {
+            updates: vec![("Paris".to_string(), 31); 3],
+            ..Default::default()
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get("Paris"), Some(31));
+
+        drop(cache);
+        let changes: Vec<_> = watch.map(|event| event.new).collect().await;
+        assert_eq!(changes, vec![30, 31]);
+    }
+
+    #[tokio::test]
+    async fn test_try_new_fails_fast() {
+        let result = StreamCache::try_new(DeadApi).await;
+        assert_eq!(
+            result.err(),
+            Some(CacheError::Fetch("connection refused".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_try_new_starts_populated() {
+        let api = MockApi {
+            fetched: hashmap! { "Berlin".to_string() => 29 },
+            updates: vec![("London".to_string(), 27)],
+            ..Default::default()
+        };
+        let cache = StreamCache::try_new(api.clone()).await.unwrap();
+        assert_eq!(cache.get("Berlin"), Some(29));
+
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get("London"), Some(27));
+        assert_eq!(api.fetch_calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_watcher_count() {
+        let cache = StreamCache::new(TestApi::default());
+        assert_eq!(cache.watcher_count(), 0);
+
+        let first = cache.watch();
+        let second = cache.updates();
+        assert_eq!(cache.watcher_count(), 2);
+
+        drop(first);
+        assert_eq!(cache.watcher_count(), 1);
+        drop(second);
+        assert_eq!(cache.watcher_count(), 0);
+    }
+
+    fn fetch_with_duplicates() -> UpdatesApi {
+        UpdatesApi {
+            fetched: vec![
+                Update::Single("Paris".to_string(), 30),
+                Update::Single("London".to_string(), 27),
+                Update::Single("Paris".to_string(), 35),
+                Update::Single("Paris".to_string(), 33),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_keys_within_fetch() {
+        let cache = StreamCache::builder()
+            .conflict_policy(ConflictPolicy::KeepMax)
+            .build(fetch_with_duplicates());
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get("Paris"), Some(35));
+        assert_eq!(cache.get("London"), Some(27));
+
+        // Under the default policy the last duplicate wins.
+        let cache = StreamCache::new(fetch_with_duplicates());
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get("Paris"), Some(33));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_diff_lists_changed_keys() {
+        let cache = StreamCache::builder()
+            .conflict_policy(ConflictPolicy::KeepMax)
+            .skip_initial_fetch(true)
+            .build(MockApi {
+                fetched: hashmap! {
+                    "Paris".to_string() => 32,
+                    "London".to_string() => 27,
+                    "Berlin".to_string() => 29,
+                },
+                ..Default::default()
+            });
+        cache.insert("Paris", 30).unwrap();
+        cache.insert("London", 27).unwrap();
+
+        let mut diff = cache.refresh_diff().await.unwrap();
+        diff.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(
+            diff,
+            vec![
+                ChangeEvent {
+                    key: "Berlin".to_string(),
+                    old: None,
+                    new: 29
+                },
+                ChangeEvent {
+                    key: "Paris".to_string(),
+                    old: Some(30),
+                    new: 32
+                },
+            ]
+        );
+        assert!(cache.refresh_diff().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_with_initial_seeds_before_updates() {
+        let cache = StreamCache::with_initial(
+            TestApi::default(),
+            hashmap! {
+                "Paris".to_string() => 10,
+                "Rome".to_string() => 25,
+            },
+        );
+        assert_eq!(cache.get("Paris"), Some(10));
+
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(cache.get("Paris"), Some(32));
+        assert_eq!(cache.get("Rome"), Some(25));
+        assert_eq!(cache.get("Berlin"), Some(29));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_error_dedup_collapses_repeats() {
+        let (on_error, reported) = error_recorder();
+        let _cache = StreamCache::builder()
+            .on_error(on_error)
+            .error_dedup_window(Duration::from_secs(1))
+            .build(FailingStreamApi { errors: 100 });
+
+        time::sleep(Duration::from_secs(2)).await;
+        let error = CacheError::Subscribe("upstream down".to_string());
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec![(error.clone(), 1), (error, 99)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors_reported_without_dedup() {
+        let (on_error, reported) = error_recorder();
+        let _cache = StreamCache::builder()
+            .on_error(on_error)
+            .build(FailingStreamApi { errors: 3 });
+
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(reported.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_condition() {
+        let cache = StreamCache::new(TestApi::default());
+        assert_eq!(cache.wait_for("Paris", |t| t >= 32).await, Some(32));
+        // Already satisfied, so it resolves straight away.
+        assert_eq!(cache.wait_for("London", |t| t > 20).await, Some(27));
+
+        let missed = cache.wait_for_timeout("Paris", |t| t >= 100, Duration::from_millis(50));
+        assert_eq!(missed.await, None);
+    }
+
+    fn glitchy_sensor() -> MockApi {
+        MockApi {
+            updates: vec![("Paris".to_string(), 30), ("Paris".to_string(), u64::MAX)],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_value_bounds_drop() {
+        let cache = StreamCache::builder()
+            .with_value_bounds(0, 60, OnViolation::Drop)
+            .build(glitchy_sensor());
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get("Paris"), Some(30));
+        assert_eq!(
+            cache.insert_with_outcome("Rome", 100),
+            Ok(InsertOutcome::Dropped)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_value_bounds_clamp() {
+        let cache = StreamCache::builder()
+            .with_value_bounds(0, 60, OnViolation::Clamp)
+            .build(glitchy_sensor());
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get("Paris"), Some(60));
+    }
+
+    #[tokio::test]
+    async fn test_value_bounds_error() {
+        let (on_error, reported) = error_recorder();
+        let cache = StreamCache::builder()
+            .with_value_bounds(0, 60, OnViolation::Error)
+            .on_error(on_error)
+            .build(glitchy_sensor());
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(cache.get("Paris"), Some(30));
+        let error = CacheError::OutOfRange {
+            key: "Paris".to_string(),
+            value: u64::MAX,
+        };
+        assert_eq!(*reported.lock().unwrap(), vec![(error, 1)]);
+        assert!(matches!(
+            cache.insert("Rome", 100),
+            Err(CacheError::OutOfRange { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reset_metrics() {
+        let cache = StreamCache::builder()
+            .with_value_bounds(0, 60, OnViolation::Error)
+            .build_empty();
+        cache.insert("Paris", 20).unwrap();
+        cache.insert("Paris", 25).unwrap();
+        assert!(cache.insert("Rome", 100).is_err());
+        cache.get("Paris");
+        cache.get("Rome");
+        assert_eq!(
+            cache.metrics(),
+            Metrics {
+                hits: 1,
+                misses: 1,
+                updates: 2,
+                errors: 1,
+                ..Metrics::default()
+            }
+        );
+
+        cache.reset_metrics();
+        assert_eq!(cache.metrics(), Metrics::default());
+        assert_eq!(cache.snapshot(), hashmap! { "Paris".to_string() => 25 });
+    }
+
+    struct MapFallback(HashMap<String, u64>);
+
+    #[async_trait]
+    impl Fallback for MapFallback {
+        async fn get(&self, key: &str) -> Option<u64> {
+            self.0.get(key).copied()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_or_fallback_promotes() {
+        let fallback = MapFallback(hashmap! { "Oslo".to_string() => 4 });
+        let cache = StreamCache::builder()
+            .fallback(fallback, true)
+            .build_empty();
+        assert_eq!(cache.get("Oslo"), None);
+
+        assert_eq!(cache.get_or_fallback("Oslo").await, Some(4));
+        assert_eq!(cache.get("Oslo"), Some(4));
+        assert_eq!(cache.get_or_fallback("Lima").await, None);
+    }
+
+    /// Streams `updates`, then signals `drained` once the worker asks for
+    /// more, i.e. once every update has been queued.
+    #[derive(Clone)]
+    struct BurstApi {
+        updates: Vec<(City, Temperature)>,
+        drained: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl Api for BurstApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            Ok(HashMap::new())
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            let drained = Arc::clone(&self.drained);
+            futures::stream::iter(self.updates.clone().into_iter().map(Ok))
+                .chain(futures::stream::once(async move {
+                    drained.notify_one();
+                    future::pending().await
+                }))
+                .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_graceful_drains_queue() {
+        let api = BurstApi {
+            updates: (0..200).map(|i| (format!("City{i}"), i)).collect(),
+            drained: Default::default(),
+        };
+        let drained = Arc::clone(&api.drained);
+        let cache = StreamCache::builder().update_buffer(256).build(api);
+        drained.notified().await;
+
+        cache.shutdown_graceful().await;
+        assert_eq!(cache.len(), 200);
+        assert_eq!(cache.get("City199"), Some(199));
+    }
+
+    /// The first subscription fails midway; later ones stream Rome.
+    #[derive(Clone, Default)]
+    struct FlakyStreamApi {
+        subscribes: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Api for FlakyStreamApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            Ok(HashMap::new())
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            let items = if self.subscribes.fetch_add(1, Ordering::SeqCst) == 0 {
+                vec![
+                    Ok(("Paris".to_string(), 1)),
+                    Err("connection reset".to_string()),
+                    Ok(("Paris".to_string(), 2)),
+                ]
+            } else {
+                vec![Ok(("Rome".to_string(), 3))]
+            };
+            futures::stream::iter(items)
+                .chain(futures::stream::pending())
+                .boxed()
+        }
+    }
+
+    async fn run_flaky(policy: SubscriptionErrorPolicy) -> (StreamCache<FlakyStreamApi>, usize) {
+        let api = FlakyStreamApi::default();
+        let subscribes = Arc::clone(&api.subscribes);
+        let cache = StreamCache::builder()
+            .subscription_error_policy(policy)
+            .build(api);
+        time::sleep(Duration::from_secs(1)).await;
+        (cache, subscribes.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_subscription_error_skip() {
+        let (cache, subscribes) = run_flaky(SubscriptionErrorPolicy::Skip).await;
+        assert_eq!(subscribes, 1);
+        assert_eq!(cache.get("Paris"), Some(2));
+        assert_eq!(
+            cache.last_error(),
+            Some(CacheError::Subscribe("connection reset".to_string()))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_subscription_error_reconnect() {
+        let (cache, subscribes) = run_flaky(SubscriptionErrorPolicy::Reconnect).await;
+        assert_eq!(subscribes, 2);
+        assert_eq!(cache.get("Paris"), Some(1));
+        assert_eq!(cache.get("Rome"), Some(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_subscription_error_fail() {
+        let (cache, subscribes) = run_flaky(SubscriptionErrorPolicy::Fail).await;
+        assert_eq!(subscribes, 1);
+        assert_eq!(cache.get("Paris"), Some(1));
+        assert_eq!(cache.get("Rome"), None);
+        assert_eq!(
+            cache.last_error(),
+            Some(CacheError::Subscribe("connection reset".to_string()))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_get_with_age_advances() {
+        let cache = StreamCache::builder()
+            .ttl(Duration::from_secs(60))
+            .build_empty();
+        cache.insert("Paris", 20).unwrap();
+        assert_eq!(cache.get_with_age("Paris"), Some((20, Duration::ZERO)));
+
+        time::advance(Duration::from_secs(15)).await;
+        assert_eq!(
+            cache.get_with_age("Paris"),
+            Some((20, Duration::from_secs(15)))
+        );
+
+        cache.insert("Paris", 21).unwrap();
+        time::advance(Duration::from_secs(5)).await;
+        assert_eq!(
+            cache.get_with_age("Paris"),
+            Some((21, Duration::from_secs(5)))
+        );
+        assert_eq!(cache.get_with_age("Rome"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watch_batched_coalesces() {
+        let cache = StreamCache::builder().build_empty();
+        let mut batches = cache.watch_batched(Duration::from_secs(1));
+        cache.insert("Paris", 20).unwrap();
+        cache.insert("Paris", 21).unwrap();
+        cache.insert("Rome", 30).unwrap();
+        cache.insert("Paris", 22).unwrap();
+
+        let batch = batches.next().await.unwrap();
+        assert_eq!(
+            batch,
+            vec![
+                ChangeEvent {
+                    key: "Paris".to_string(),
+                    old: None,
+                    new: 22,
+                },
+                ChangeEvent {
+                    key: "Rome".to_string(),
+                    old: None,
+                    new: 30,
+                },
+            ]
+        );
+
+        time::advance(Duration::from_millis(500)).await;
+        cache.insert("Rome", 31).unwrap();
+        let start = Instant::now();
+        let batch = batches.next().await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+        assert_eq!(
+            batch,
+            vec![ChangeEvent {
+                key: "Rome".to_string(),
+                old: Some(30),
+                new: 31,
+            }]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sliding_ttl_keeps_read_keys_alive() {
+        let cache = StreamCache::builder()
+            .sliding_ttl(Duration::from_secs(10))
+            .build_empty();
+        cache.insert("Paris", 20).unwrap();
+        cache.insert("Rome", 30).unwrap();
+
+        for _ in 0..5 {
+            time::advance(Duration::from_secs(6)).await;
+            assert_eq!(cache.get("Paris"), Some(20));
+        }
+        assert_eq!(cache.get("Rome"), None);
+    }
+
+    #[tokio::test]
+    async fn test_join_after_bounded_stream() {
+        let api = UpdatesApi {
+            updates: vec![
+                Update::Single("Paris".to_string(), 20),
+                Update::Single("Rome".to_string(), 30),
+            ],
+            ..Default::default()
+        };
+        let cache = StreamCache::new(api);
+        let changes = cache.watch();
+
+        assert_eq!(cache.join().await, Ok(()));
+        let applied: Vec<_> = changes.map(|change| change.key).collect().await;
+        assert_eq!(applied, vec!["Paris", "Rome"]);
+    }
+
+    #[tokio::test]
+    async fn test_join_reports_terminal_error() {
+        let cache = StreamCache::builder()
+            .subscription_error_policy(SubscriptionErrorPolicy::Fail)
+            .build(FailingStreamApi { errors: 1 });
+        assert_eq!(
+            cache.join().await,
+            Err(CacheError::Subscribe("upstream down".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_key_normalizer() {
+        let api = UpdatesApi {
+            updates: vec![
+                Update::Single("paris".to_string(), 20),
+                Update::Single("PARIS".to_string(), 21),
+                Update::Single("Paris".to_string(), 22),
+            ],
+            ..Default::default()
+        };
+        let cache = StreamCache::builder()
+            .with_key_normalizer(Arc::new(|key: &str| key.to_lowercase()))
+            .build(api);
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get("pArIs"), Some(22));
+        cache.insert("PARIS", 23).unwrap();
+        assert_eq!(cache.get("paris"), Some(23));
+        assert_eq!(cache.with_read(|store| store.get("Paris")), Some(23));
+        assert!(cache.with_read(|store| store.contains_key("PARIS")));
+        assert_eq!(cache.remove("Paris"), Some(23));
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_empty_warn_after_reports_no_data_once() {
+        let (on_error, reported) = error_recorder();
+        let silent = BurstApi {
+            updates: Vec::new(),
+            drained: Default::default(),
+        };
+        let _cache = StreamCache::builder()
+            .on_error(on_error)
+            .empty_warn_after(Duration::from_secs(5))
+            .build(silent);
+
+        time::sleep(Duration::from_secs(4)).await;
+        assert!(reported.lock().unwrap().is_empty());
+        time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(*reported.lock().unwrap(), vec![(CacheError::NoData, 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_extend_applies_policy_under_one_lock() {
+        let cache = StreamCache::builder()
+            .conflict_policy(ConflictPolicy::KeepMax)
+            .build_empty();
+        cache.insert("Paris", 30).unwrap();
+        cache.insert("Rome", 20).unwrap();
+        let before = lock_count(&cache);
+
+        cache.extend(vec![
+            ("Paris".to_string(), 25),
+            ("Rome".to_string(), 28),
+            ("Oslo".to_string(), 4),
+        ]);
+
+        assert_eq!(lock_count(&cache) - before, 1);
+        assert_eq!(
+            cache.snapshot(),
+            hashmap! {
+                "Paris".to_string() => 30,
+                "Rome".to_string() => 28,
+                "Oslo".to_string() => 4,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resync_rebuilds_from_fetch() {
+        let api = UpdatesApi {
+            fetched: vec![Update::Single("Oslo".to_string(), 4)],
+            updates: vec![
+                Update::Single("Paris".to_string(), 20),
+                Update::Single("Rome".to_string(), 30),
+                Update::Resync,
+                Update::Single("Rome".to_string(), 31),
+            ],
+        };
+        let cache = StreamCache::builder().skip_initial_fetch(true).build(api);
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(
+            cache.snapshot(),
+            hashmap! {
+                "Oslo".to_string() => 4,
+                "Rome".to_string() => 31,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shared_store_roundtrip() {
+        let cache = StreamCache::builder().build_empty();
+        cache.insert("Paris", 20).unwrap();
+        let store = cache.shared_store();
+        {
+            let mut map = store.lock().unwrap();
+            assert_eq!(map.get("Paris"), Some(20));
+            map.insert("Rome".to_string(), 30).unwrap();
+            map.remove("Paris");
+        }
+
+        assert_eq!(cache.get("Rome"), Some(30));
+        assert_eq!(cache.get("Paris"), None);
+        assert_eq!(cache.snapshot(), hashmap! { "Rome".to_string() => 30 });
+    }
+
+    #[tokio::test]
+    async fn test_shared_store_writes_reach_watchers_and_the_aggregate() {
+        let sum: AggregateFn<u64> = Arc::new(|sum, _, old, new| sum - old.unwrap_or(0) + new);
+        let cache = StreamCache::builder()
+            .with_aggregate(0, sum)
+            .with_key_normalizer(Arc::new(|key: &str| key.to_lowercase()))
+            .shards(4)
+            .build_empty();
+        let watch = cache.watch();
+        cache.insert("Paris", 20).unwrap();
+        {
+            let store = cache.shared_store();
+            let mut map = store.lock().unwrap();
+            assert_eq!(map.insert("ROME".to_string(), 30), Ok(None));
+            assert_eq!(map.insert("Paris".to_string(), 25), Ok(Some(20)));
+            assert_eq!(map.len(), 2);
+        }
+
+        assert_eq!(cache.get("rome"), Some(30));
+        assert_eq!(cache.aggregate::<u64>(), Some(55));
+        let events: Vec<_> = watch
+            .take(3)
+            .map(|event| (event.key, event.new))
+            .collect()
+            .await;
+        assert_eq!(
+            events,
+            vec![
+                ("paris".to_string(), 20),
+                ("rome".to_string(), 30),
+                ("paris".to_string(), 25),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shrink_to_fit_releases_capacity() {
+        let cache = StreamCache::builder().build_empty();
+        cache.extend((0..10_000).map(|i| (format!("City{i}"), i)));
+        for i in 10..10_000 {
+            cache.remove(&format!("City{i}"));
+        }
+        let before = cache.inner.lock_shard(0).unwrap().values.capacity();
+
+        cache.shrink_to_fit();
+        let after = cache.inner.lock_shard(0).unwrap().values.capacity();
+        assert!(after * 100 < before, "{before} -> {after}");
+        assert_eq!(cache.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_get_as_converts() {
+        let cache = StreamCache::builder().build_empty();
+        cache.insert("Paris", 215).unwrap();
+        assert_eq!(
+            cache.get_as("Paris", |tenths| tenths as f64 / 10.0),
+            Some(21.5)
+        );
+        assert_eq!(cache.get_as("Rome", |tenths| tenths as f64 / 10.0), None);
+    }
+
+    #[tokio::test]
+    async fn test_backfill_adds_only_missing_keys() {
+        let api = UpdatesApi {
+            fetched: vec![
+                Update::Single("Berlin".to_string(), 29),
+                Update::Single("London".to_string(), 10),
+                Update::Single("Paris".to_string(), 32),
+            ],
+            updates: vec![Update::Single("London".to_string(), 27)],
+        };
+        let cache = StreamCache::builder().skip_initial_fetch(true).build(api);
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.len(), 1);
+
+        assert_eq!(cache.backfill().await, Ok(2));
+        assert_eq!(
+            cache.snapshot(),
+            hashmap! {
+                "Berlin".to_string() => 29,
+                "London".to_string() => 27,
+                "Paris".to_string() => 32,
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout_detects_stall() {
+        let (on_error, reported) = error_recorder();
+        let api = BurstApi {
+            updates: vec![("Paris".to_string(), 20)],
+            drained: Default::default(),
+        };
+        let cache = StreamCache::builder()
+            .idle_timeout(Duration::from_secs(5))
+            .subscription_error_policy(SubscriptionErrorPolicy::Fail)
+            .on_error(on_error)
+            .build(api);
+
+        time::sleep(Duration::from_secs(4)).await;
+        assert!(reported.lock().unwrap().is_empty());
+        let idle = CacheError::Idle(Duration::from_secs(5));
+        assert_eq!(cache.join().await, Err(idle.clone()));
+        assert_eq!(*reported.lock().unwrap(), vec![(idle, 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_recent_lists_latest_updates() {
+        let api = UpdatesApi {
+            updates: vec![
+                Update::Single("Berlin".to_string(), 29),
+                Update::Single("London".to_string(), 27),
+                Update::Single("Paris".to_string(), 32),
+                Update::Single("Berlin".to_string(), 30),
+            ],
+            ..Default::default()
+        };
+        let cache = StreamCache::new(api);
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(
+            cache.recent(2),
+            vec![("Berlin".to_string(), 30), ("Paris".to_string(), 32)]
+        );
+        assert_eq!(cache.recent(10).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_from_subscribe() {
+        let api = UpdatesApi {
+            fetched: vec![Update::Single("Oslo".to_string(), 4)],
+            updates: vec![
+                Update::Single("Paris".to_string(), 20),
+                Update::Single("Rome".to_string(), 30),
+                Update::SnapshotComplete,
+                Update::Single("Paris".to_string(), 21),
+            ],
+        };
+        let cache = StreamCache::builder()
+            .snapshot_from_subscribe(true)
+            .build(api);
+
+        cache.wait_ready().await;
+        assert_eq!(cache.get("Rome"), Some(30));
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get("Paris"), Some(21));
+        assert_eq!(cache.get("Oslo"), None);
+    }
+
+    /// Holds an `Rc`, so it can only back a `LocalStreamCache`.
+    #[derive(Clone)]
+    struct LocalTestApi {
+        fetches: Rc<RefCell<usize>>,
+    }
+
+    #[async_trait(?Send)]
+    impl LocalApi for LocalTestApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            *self.fetches.borrow_mut() += 1;
+            Ok(hashmap! {
+                "Berlin".to_string() => 29,
+                "Paris".to_string() => 10,
+            })
+        }
+
+        async fn subscribe(&self) -> LocalBoxStream<'static, Result<(City, Temperature), String>> {
+            futures::stream::iter(vec![
+                Ok(("Paris".to_string(), 32)),
+                Err("Subscription error".to_string()),
+            ])
+            .boxed_local()
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_local_stream_cache() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let api = LocalTestApi {
+                    fetches: Default::default(),
+                };
+                let fetches = Rc::clone(&api.fetches);
+                let cache = LocalStreamCache::new(api);
+                time::sleep(Duration::from_millis(50)).await;
+
+                assert_eq!(*fetches.borrow(), 1);
+                assert_eq!(cache.get("Berlin"), Some(29));
+                assert_eq!(cache.get("Paris"), Some(32));
+                assert_eq!(cache.insert("London", 27), None);
+                assert_eq!(cache.len(), 3);
+            })
+            .await;
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn test_local_stream_cache_surface() {
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                let api = LocalTestApi {
+                    fetches: Default::default(),
+                };
+                let fetches = Rc::clone(&api.fetches);
+                let (on_error, reported) = error_recorder();
+                let cache = LocalStreamCache::builder()
+                    .ttl(Duration::from_secs(60))
+                    .on_error(on_error)
+                    .build(api);
+                let changes = cache.watch();
+                time::sleep(Duration::from_millis(50)).await;
+
+                assert_eq!(cache.get("Paris"), Some(32));
+                let status = cache.source_status();
+                assert_eq!(status.len(), 1);
+                assert!(status[0].fetched && status[0].streamed);
+                let err = CacheError::Subscribe("Subscription error".to_string());
+                assert_eq!(status[0].last_error, Some(err.clone()));
+                assert_eq!(cache.last_error(), Some(err));
+                assert_eq!(reported.lock().unwrap().len(), 1);
+
+                // A refetch fills only missing keys.
+                cache.remove("Berlin");
+                cache.fetch_now().await.unwrap();
+                assert_eq!(*fetches.borrow(), 2);
+                assert_eq!(cache.get("Berlin"), Some(29));
+                assert_eq!(cache.get("Paris"), Some(32));
+
+                time::sleep(Duration::from_secs(60)).await;
+                assert!(cache.is_empty());
+                cache.shutdown_graceful().await;
+
+                // Dropping the last handle ends the watch.
+                drop(cache);
+                let changes: Vec<_> = changes
+                    .map(|change| (change.key, change.new))
+                    .collect()
+                    .await;
+                assert!(changes.contains(&("Paris".to_string(), 32)));
+                assert_eq!(changes.iter().filter(|(key, _)| key == "Berlin").count(), 2);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_source_status_per_source() {
+        let cache = StreamCache::builder().build_empty();
+        cache.add_source(
+            "healthy",
+            MockApi {
+                fetched: hashmap! { "Paris".to_string() => 20 },
+                updates: vec![("Rome".to_string(), 30)],
+                ..Default::default()
+            },
+        );
+        cache.add_source("broken", DeadApi);
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(
+            cache.source_status(),
+            vec![
+                SourceStatus {
+                    id: Some("healthy".to_string()),
+                    fetched: true,
+                    streamed: true,
+                    last_error: None,
+                },
+                SourceStatus {
+                    id: Some("broken".to_string()),
+                    fetched: false,
+                    streamed: false,
+                    last_error: Some(CacheError::Fetch("connection refused".to_string())),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_replace_all_is_atomic() {
+        let old: HashMap<_, _> = (0..100).map(|i| (format!("Old{i}"), i)).collect();
+        let new: HashMap<_, _> = (0..100).map(|i| (format!("New{i}"), i)).collect();
+        let cache = Arc::new(StreamCache::builder().initial(old.clone()).build_empty());
+
+        let reader = {
+            let cache = Arc::clone(&cache);
+            let (old, new) = (old.clone(), new.clone());
+            tokio::spawn(async move {
+                for _ in 0..1000 {
+                    let seen = cache.snapshot();
+                    assert!(seen == old || seen == new);
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        for i in 0..200 {
+            cache.replace_all(if i % 2 == 0 { new.clone() } else { old.clone() });
+            tokio::task::yield_now().await;
+        }
+        reader.await.unwrap();
+        assert_eq!(cache.snapshot(), old);
+    }
+
+    #[tokio::test]
+    async fn test_update_counts_and_hot_keys() {
+        let api = MockApi {
+            updates: vec![
+                ("Paris".to_string(), 20),
+                ("Rome".to_string(), 30),
+                ("Paris".to_string(), 21),
+                ("Paris".to_string(), 22),
+                ("Oslo".to_string(), 4),
+            ],
+            ..Default::default()
+        };
+        let cache = StreamCache::new(api);
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(cache.update_count("Paris"), Some(3));
+        assert_eq!(cache.update_count("Rome"), Some(1));
+        assert_eq!(cache.update_count("Berlin"), None);
+        assert_eq!(
+            cache.hot_keys(2),
+            vec![("Paris".to_string(), 3), ("Oslo".to_string(), 1)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_async_resolver_keeps_max_in_order() {
+        let resolver: AsyncResolver = Arc::new(|_key: &str, existing: u64, incoming: u64| {
+            async move {
+                time::sleep(Duration::from_millis(100)).await;
+                existing.max(incoming)
+            }
+            .boxed()
+        });
+        let api = MockApi {
+            updates: vec![
+                ("Paris".to_string(), 20),
+                ("Paris".to_string(), 25),
+                ("Paris".to_string(), 22),
+                ("Rome".to_string(), 30),
+            ],
+            ..Default::default()
+        };
+        let cache = StreamCache::builder()
+            .conflict_policy(ConflictPolicy::Async(resolver))
+            .build(api);
+        let changes = cache.watch();
+        time::sleep(Duration::from_secs(1)).await;
+
+        assert_eq!(cache.get("Paris"), Some(25));
+        assert_eq!(cache.get("Rome"), Some(30));
+        let applied: Vec<_> = changes
+            .take(3)
+            .map(|change| (change.key, change.new))
+            .collect()
+            .await;
+        assert_eq!(
+            applied,
+            vec![
+                ("Paris".to_string(), 20),
+                ("Paris".to_string(), 25),
+                ("Rome".to_string(), 30),
+            ]
+        );
+    }
+
+    fn silent_api() -> BurstApi {
+        BurstApi {
+            updates: vec![("Paris".to_string(), 20)],
+            drained: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_group_shutdown_stops_all() {
+        let group = CacheGroup::with_limit(3);
+        let caches: Vec<_> = (0..3)
+            .map(|_| group.new_cache(silent_api()).unwrap())
+            .collect();
+        assert_eq!(
+            group.new_cache(silent_api()).err(),
+            Some(CacheError::GroupFull)
+        );
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(group.task_count(), 3);
+
+        group.shutdown().await;
+        assert_eq!(group.task_count(), 0);
+        assert!(caches.iter().all(|cache| cache.get("Paris") == Some(20)));
+    }
+
+    #[tokio::test]
+    async fn test_peek_leaves_metrics_alone() {
+        let cache = StreamCache::builder().build_empty();
+        cache.insert("Paris", 20).unwrap();
+        let before = cache.metrics();
+        for _ in 0..5 {
+            assert_eq!(cache.peek("Paris"), Some(20));
+            assert_eq!(cache.peek("Rome"), None);
+        }
+        assert_eq!(cache.metrics(), before);
+
+        cache.get("Paris");
+        cache.get("Rome");
+        let after = cache.metrics();
+        assert_eq!(
+            (after.hits, after.misses),
+            (before.hits + 1, before.misses + 1)
+        );
+    }
+
+    /// Streams fixed readings, honoring `subscribe_keys`.
+    #[derive(Clone)]
+    struct FilteringApi;
+
+    #[async_trait]
+    impl Api for FilteringApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            Ok(HashMap::new())
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            self.subscribe_keys(&["Berlin".into(), "London".into(), "Paris".into()])
+                .await
+        }
+
+        async fn subscribe_keys(
+            &self,
+            keys: &[City],
+        ) -> BoxStream<Result<(City, Temperature), String>> {
+            let readings = vec![
+                ("Berlin".to_string(), 29),
+                ("London".to_string(), 27),
+                ("Paris".to_string(), 32),
+            ];
+            let keys = keys.to_vec();
+            futures::stream::iter(readings)
+                .filter(move |(city, _)| futures::future::ready(keys.contains(city)))
+                .map(Ok)
+                .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interest_uses_subscribe_keys() {
+        let cache = StreamCache::builder()
+            .interest(["Paris", "London"])
+            .build(FilteringApi);
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            cache.snapshot(),
+            hashmap! {
+                "London".to_string() => 27,
+                "Paris".to_string() => 32,
+            }
+        );
+    }
+
+    /// Fails `failures` fetches, then serves Berlin.
+    #[derive(Clone)]
+    struct WarmingUpApi {
+        failures: usize,
+        fetch_calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Api for WarmingUpApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            if self.fetch_calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err("warming up".to_string());
+            }
+            Ok(hashmap! { "Berlin".to_string() => 29 })
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            futures::stream::pending().boxed()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_initial_fetch_retries() {
+        let api = WarmingUpApi {
+            failures: 2,
+            fetch_calls: Default::default(),
+        };
+        let fetch_calls = Arc::clone(&api.fetch_calls);
+        let start = Instant::now();
+        let cache = StreamCache::builder()
+            .initial_fetch_retries(3, Duration::from_secs(1))
+            .build(api);
+
+        cache.wait_ready().await;
+        assert_eq!(cache.get("Berlin"), Some(29));
+        assert_eq!(fetch_calls.load(Ordering::SeqCst), 3);
+        // Backoffs of 1s and 2s.
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_lock_contention_is_measured() {
+        let cache = StreamCache::builder()
+            .measure_lock_contention(true)
+            .build_empty();
+        cache.get("Paris");
+        assert_eq!(cache.metrics().lock_wait_total, Duration::ZERO);
+
+        let store = cache.shared_store();
+        let (locked, wait_locked) = std::sync::mpsc::channel();
+        let holder = std::thread::spawn(move || {
+            let _map = store.lock().unwrap();
+            locked.send(()).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+        });
+        wait_locked.recv().unwrap();
+        cache.get("Paris");
+        holder.join().unwrap();
+
+        let metrics = cache.metrics();
+        assert!(metrics.lock_wait_max >= Duration::from_millis(20));
+        assert_eq!(metrics.lock_wait_total, metrics.lock_wait_max);
+    }
+
+    #[tokio::test]
+    async fn test_on_shutdown_snapshot() {
+        let (sender, receiver) = oneshot::channel();
+        let api = UpdatesApi {
+            updates: vec![
+                Update::Single("Paris".to_string(), 20),
+                Update::Single("Rome".to_string(), 30),
+                Update::Single("Paris".to_string(), 21),
+            ],
+            ..Default::default()
+        };
+        let cache = StreamCache::builder()
+            .on_shutdown_snapshot(sender)
+            .build(api);
+
+        let snapshot = receiver.await.unwrap();
+        assert_eq!(
+            snapshot,
+            hashmap! {
+                "Paris".to_string() => 21,
+                "Rome".to_string() => 30,
+            }
+        );
+        assert_eq!(snapshot, cache.snapshot());
+    }
+
+    #[tokio::test]
+    async fn test_btree_store_range() {
+        let cache = StreamCache::builder()
+            .store(BTreeStore::default())
+            .initial(hashmap! {
+                "London".to_string() => 15,
+                "Berlin".to_string() => 18,
+                "Paris".to_string() => 20,
+                "Rome".to_string() => 30,
+            })
+            .build_empty();
+
+        assert_eq!(
+            cache.range("Berlin".to_string().."Paris".to_string()),
+            Ok(vec![("Berlin".to_string(), 18), ("London".to_string(), 15)])
+        );
+        assert_eq!(
+            cache.range("Rome".to_string().."Berlin".to_string()),
+            Ok(Vec::new())
+        );
+        assert_eq!(cache.get("Paris"), Some(20));
+
+        let unordered = StreamCache::builder().build_empty();
+        unordered.insert("Paris", 20).unwrap();
+        assert_eq!(unordered.range(..), Err(CacheError::Unordered));
+    }
+
+    #[tokio::test]
+    async fn test_range_over_cities() {
+        let cache = StreamCache::builder()
+            .store(BTreeStore::default())
+            .initial(hashmap! {
+                "Paris".to_string() => 31,
+                "Berlin".to_string() => 29,
+                "London".to_string() => 27,
+            })
+            .build_empty();
+
+        assert_eq!(
+            cache.range("Berlin".to_string()..="London".to_string()),
+            Ok(vec![("Berlin".to_string(), 29), ("London".to_string(), 27)])
+        );
+        assert_eq!(
+            cache.range("Lon".to_string().."Loo".to_string()),
+            Ok(vec![("London".to_string(), 27)])
+        );
+        assert_eq!(
+            cache.range("C".to_string()..),
+            Ok(vec![("London".to_string(), 27), ("Paris".to_string(), 31)])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_decay_on_read() {
+        let cache = StreamCache::builder()
+            .with_decay(Arc::new(|value, age: Duration| {
+                value.saturating_sub(age.as_secs())
+            }))
+            .build_empty();
+        cache.insert("Paris", 20).unwrap();
+
+        assert_eq!(cache.get("Paris"), Some(20));
+        time::advance(Duration::from_secs(5)).await;
+        assert_eq!(cache.get("Paris"), Some(15));
+        time::advance(Duration::from_secs(5)).await;
+        assert_eq!(cache.get("Paris"), Some(10));
+        assert_eq!(cache.peek("Paris"), Some(10));
+        assert_eq!(cache.snapshot()["Paris"], 20);
+
+        cache.insert("Paris", 20).unwrap();
+        assert_eq!(cache.get("Paris"), Some(20));
+    }
+
+    #[tokio::test]
+    async fn test_sentinel_keeps_real_value() {
+        let api = UpdatesApi {
+            updates: vec![
+                Update::Single("Paris".to_string(), 20),
+                Update::Single("Paris".to_string(), u64::MAX),
+                Update::Single("Rome".to_string(), u64::MAX),
+            ],
+            ..Default::default()
+        };
+        let cache = StreamCache::builder().sentinel(u64::MAX).build(api);
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(cache.get("Paris"), Some(20));
+        assert_eq!(cache.get("Rome"), None);
+        assert_eq!(
+            cache.insert_with_outcome("Paris", u64::MAX),
+            Ok(InsertOutcome::Dropped)
+        );
+        assert_eq!(cache.get("Paris"), Some(20));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_once() {
+        let api = TestApi::default();
+        api.signal.notify_one();
+        assert_eq!(
+            fetch_once(&api).await,
+            Ok(hashmap! {
+                "Berlin".to_string() => 29,
+                "Paris".to_string() => 31,
+            })
+        );
+
+        let failing = TestApi {
+            fetch_error: Some("down".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            fetch_once(&failing).await,
+            Err(CacheError::Fetch("down".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_diff_snapshots() {
+        let old = hashmap! {
+            "Berlin".to_string() => 29,
+            "Paris".to_string() => 31,
+            "London".to_string() => 27,
+        };
+        let new = hashmap! {
+            "Paris".to_string() => 32,
+            "London".to_string() => 27,
+            "Rome".to_string() => 30,
+        };
+
+        let expected = CacheDiff {
+            added: hashmap! { "Rome".to_string() => 30 },
+            removed: hashmap! { "Berlin".to_string() => 29 },
+            changed: hashmap! { "Paris".to_string() => (31, 32) },
+        };
+        assert_eq!(diff(&old, &new), expected);
+        assert_eq!(diff(&new, &new), CacheDiff::default());
+
+        let cache = StreamCache::builder().initial(old).build_empty();
+        let before = cache.checkpoint();
+        cache.insert("Paris", 32).unwrap();
+        cache.remove("Berlin");
+        cache.insert("Rome", 30).unwrap();
+        assert_eq!(before.diff(&cache.checkpoint()), expected);
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_running_sum() {
+        let api = UpdatesApi {
+            updates: vec![
+                Update::Single("Paris".to_string(), 20),
+                Update::Single("Rome".to_string(), 30),
+                Update::Batch(vec![("Paris".to_string(), 25), ("Berlin".to_string(), 18)]),
+                Update::Single("Rome".to_string(), 30),
+            ],
+            ..Default::default()
+        };
+        let sum: AggregateFn<u64> = Arc::new(|sum, _, old, new| sum - old.unwrap_or(0) + new);
+        let cache = StreamCache::builder().with_aggregate(0, sum).build(api);
+        time::sleep(Duration::from_millis(50)).await;
+        cache.insert("Berlin", 20).unwrap();
+
+        assert_eq!(cache.aggregate::<u64>(), Some(75));
+        assert_eq!(
+            cache.aggregate::<u64>(),
+            Some(cache.snapshot().values().sum())
+        );
+        assert_eq!(cache.aggregate::<i64>(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_with_entry_is_a_full_write() {
+        let sum: AggregateFn<u64> = Arc::new(|sum, _, old, new| sum - old.unwrap_or(0) + new);
+        let cache = StreamCache::builder().with_aggregate(0, sum).build_empty();
+        cache.insert("Paris", 20).unwrap();
+        cache.insert("Rome", 30).unwrap();
+        time::advance(Duration::from_secs(5)).await;
+        let updates = cache.metrics().updates;
+
+        cache.with_entry("Paris", |value| *value.unwrap() += 5);
+        assert_eq!(cache.aggregate::<u64>(), Some(55));
+        assert_eq!(cache.metrics().updates, updates + 1);
+        assert_eq!(cache.get_with_age("Paris"), Some((25, Duration::ZERO)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expire_at() {
+        let cache = StreamCache::builder()
+            .sliding_ttl(Duration::from_secs(60))
+            .build_empty();
+        cache.insert("Paris", 20).unwrap();
+        cache.insert("Rome", 30).unwrap();
+
+        assert!(cache.expire_at("Paris", Instant::now() + Duration::from_secs(5)));
+        assert!(!cache.expire_at("Berlin", Instant::now()));
+        time::advance(Duration::from_secs(4)).await;
+        assert_eq!(cache.get("Paris"), Some(20));
+        time::advance(Duration::from_secs(2)).await;
+
+        assert_eq!(cache.get("Paris"), None);
+        assert_eq!(cache.get("Rome"), Some(30));
+    }
+
+    #[tokio::test]
+    async fn test_from_stream() {
+        let cache = StreamCache::from_stream(
+            futures::stream::iter(vec![
+                Ok(("Paris".to_string(), 20)),
+                Ok(("Rome".to_string(), 30)),
+                Ok(("Paris".to_string(), 21)),
+            ])
+            .boxed(),
+        );
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(cache.get("Paris"), Some(21));
+        assert_eq!(cache.get("Rome"), Some(30));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_wait_empty() {
+        let cache = StreamCache::builder()
+            .ttl(Duration::from_secs(5))
+            .build_empty();
+        cache.insert("Paris", 20).unwrap();
+        time::advance(Duration::from_secs(2)).await;
+        cache.insert("Rome", 30).unwrap();
+
+        assert!(!cache.wait_empty(Duration::from_secs(1)).await);
+        let start = Instant::now();
+        assert!(cache.wait_empty(Duration::from_secs(60)).await);
+        assert_eq!(start.elapsed(), Duration::from_secs(4));
+        assert!(cache.is_empty());
+
+        let cache = StreamCache::builder().build_empty();
+        cache.insert("Paris", 20).unwrap();
+        let (empty, _) = tokio::join!(cache.wait_empty(Duration::from_secs(60)), async {
+            time::sleep(Duration::from_secs(1)).await;
+            cache.remove("Paris");
+        });
+        assert!(empty);
+    }
+
+    #[tokio::test]
+    async fn test_api_accessor() {
+        let cache = StreamCache::builder()
+            .skip_initial_fetch(true)
+            .build(TestApi::default());
+        let api = cache.api();
+        api.signal.notify_one();
+        assert_eq!(
+            api.fetch().await,
+            Ok(hashmap! {
+                "Berlin".to_string() => 29,
+                "Paris".to_string() => 31,
+            })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stale_while_revalidate() {
+        let fetch_calls = Arc::new(AtomicUsize::new(0));
+        let api = MockApi {
+            fetched: hashmap! { "Paris".to_string() => 20 },
+            fetch_calls: Arc::clone(&fetch_calls),
+            ..Default::default()
+        };
+        let cache = StreamCache::builder()
+            .ttl(Duration::from_secs(5))
+            .stale_while_revalidate(Duration::from_secs(10))
+            .build(api);
+        time::sleep(Duration::from_millis(10)).await;
+        cache.insert("Rome", 30).unwrap();
+        assert_eq!(fetch_calls.load(Ordering::SeqCst), 1);
+
+        time::advance(Duration::from_secs(6)).await;
+        assert_eq!(cache.peek("Paris"), Some(20));
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(fetch_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.get("Paris"), Some(20));
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(fetch_calls.load(Ordering::SeqCst), 2);
+        let (value, age) = cache.get_with_age("Paris").unwrap();
+        assert_eq!(value, 20);
+        assert!(age < Duration::from_secs(5));
+
+        time::advance(Duration::from_secs(10)).await;
+        assert_eq!(cache.get("Rome"), None);
+    }
+
+    #[tokio::test]
+    async fn test_stale_read_outside_a_runtime() {
+        let fetch_calls = Arc::new(AtomicUsize::new(0));
+        let api = MockApi {
+            fetched: hashmap! { "Paris".to_string() => 20 },
+            fetch_calls: Arc::clone(&fetch_calls),
+            ..Default::default()
+        };
+        let cache = Arc::new(
+            StreamCache::builder()
+                .ttl(Duration::from_millis(20))
+                .stale_while_revalidate(Duration::from_secs(10))
+                .build(api),
+        );
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(fetch_calls.load(Ordering::SeqCst), 1);
+
+        let reader = Arc::clone(&cache);
+        let stale = std::thread::spawn(move || reader.get("Paris"))
+            .join()
+            .unwrap();
+        assert_eq!(stale, Some(20));
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(fetch_calls.load(Ordering::SeqCst), 1);
+
+        // The lock was not poisoned and a refresh can still start.
+        assert_eq!(cache.get("Paris"), Some(20));
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(fetch_calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recent_errors() {
+        let api = UpdatesApi {
+            updates: (1..=20)
+                .map(|value| Update::Single("Paris".to_string(), 100 + value))
+                .collect(),
+            ..Default::default()
+        };
+        let cache = StreamCache::builder()
+            .with_value_bounds(0, 50, OnViolation::Error)
+            .build(api);
+        time::sleep(Duration::from_millis(10)).await;
+
+        let errors = cache.recent_errors(100);
+        assert_eq!(errors.len(), ERROR_HISTORY);
+        let values: Vec<_> = errors
+            .iter()
+            .filter_map(|(_, err)| match err {
+                CacheError::OutOfRange { value, .. } => Some(*value),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(values, (105..=120).rev().collect::<Vec<_>>());
+        assert!(errors.windows(2).all(|pair| pair[0].0 >= pair[1].0));
+        assert_eq!(cache.recent_errors(2).len(), 2);
+        assert_eq!(Some(errors[0].1.clone()), cache.last_error());
+    }
+
+    #[tokio::test]
+    async fn test_sample() {
+        let entries = hashmap! {
+            "Berlin".to_string() => 29,
+            "London".to_string() => 27,
+            "Paris".to_string() => 31,
+        };
+        let cache = StreamCache::builder()
+            .initial(entries.clone())
+            .build_empty();
+
+        let sample = cache.sample(2);
+        assert_eq!(sample.len(), 2);
+        assert_ne!(sample[0].0, sample[1].0);
+        for (key, value) in &sample {
+            assert_eq!(entries.get(key), Some(value));
+        }
+        assert_eq!(cache.sample(10).len(), 3);
+        assert!(cache.sample(0).is_empty());
+    }
+
+    /// Panics if a call overlaps another; its stream never yields.
+    #[derive(Clone, Default)]
+    struct ExclusiveApi {
+        busy: Arc<AtomicBool>,
+    }
+
+    impl ExclusiveApi {
+        async fn call<T>(&self, output: T) -> T {
+            assert!(!self.busy.swap(true, Ordering::SeqCst), "concurrent call");
+            time::sleep(Duration::from_millis(20)).await;
+            self.busy.store(false, Ordering::SeqCst);
+            output
+        }
+    }
+
+    #[async_trait]
+    impl Api for ExclusiveApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            self.call(Ok(hashmap! { "Berlin".to_string() => 29 })).await
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            self.call(futures::stream::pending().boxed()).await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_serialize_api_calls() {
+        let cache = StreamCache::builder()
+            .serialize_api_calls(true)
+            .build(ExclusiveApi::default());
+        // Neither fetch waits on the stream, which never yields.
+        let both = async { tokio::join!(cache.fetch_now(), cache.fetch_now()) };
+        let (first, second) = time::timeout(Duration::from_secs(1), both).await.unwrap();
+        first.unwrap();
+        second.unwrap();
+
+        assert_eq!(cache.get("Berlin"), Some(29));
+    }
+
+    #[tokio::test]
+    async fn test_increment() {
+        let cache = StreamCache::builder().build_empty();
+        cache.insert("Paris", 20).unwrap();
+
+        assert_eq!(cache.increment("Rome", 5), Ok(5));
+        assert_eq!(cache.increment("Rome", 3), Ok(8));
+        assert_eq!(cache.increment("Paris", 2), Ok(22));
+        assert_eq!(cache.increment("Paris", u64::MAX), Ok(u64::MAX));
+        assert_eq!(cache.get("Rome"), Some(8));
+    }
+
+    #[tokio::test]
+    async fn test_increment_bypasses_keep_min() {
+        let cache = StreamCache::builder()
+            .conflict_policy(ConflictPolicy::KeepMin)
+            .build_empty();
+
+        assert_eq!(cache.increment("Rome", 5), Ok(5));
+        assert_eq!(cache.increment("Rome", 3), Ok(8));
+        assert_eq!(cache.get("Rome"), Some(8));
+    }
+
+    #[tokio::test]
+    async fn test_increment_is_not_throttled() {
+        let cache = StreamCache::builder()
+            .with_per_key_throttle(Arc::new(|_| Some(Duration::from_secs(60))))
+            .build_empty();
+
+        for _ in 0..3 {
+            cache.increment("Rome", 1).unwrap();
+        }
+        assert_eq!(cache.get("Rome"), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_to_csv() {
+        let cache = StreamCache::builder()
+            .initial(hashmap! {
+                "Paris".to_string() => 31,
+                "Berlin".to_string() => 29,
+                "Washington, D.C.".to_string() => 25,
+            })
+            .build_empty();
+
+        let mut buf = Vec::new();
+        cache.to_csv(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "key,value\nBerlin,29\nParis,31\n\"Washington, D.C.\",25\n"
+        );
+
+        let mut buf = Vec::new();
+        cache.to_csv_with_header(&mut buf, None).unwrap();
+        assert!(String::from_utf8(buf).unwrap().starts_with("Berlin,29\n"));
+    }
+
+    /// Converts every value from Celsius to Fahrenheit.
+    struct ToFahrenheit;
+
+    #[async_trait]
+    impl UpdateMiddleware for ToFahrenheit {
+        async fn on_update(&self, ctx: &mut UpdateContext) -> ControlFlow<()> {
+            ctx.value = ctx.value * 9 / 5 + 32;
+            ControlFlow::Continue(())
+        }
+    }
+
+    /// Vetoes keys starting with `prefix`, recording every value it sees.
+    struct VetoPrefix {
+        prefix: &'static str,
+        seen: Arc<Mutex<Vec<(String, u64)>>>,
+    }
+
+    #[async_trait]
+    impl UpdateMiddleware for VetoPrefix {
+        async fn on_update(&self, ctx: &mut UpdateContext) -> ControlFlow<()> {
+            self.seen.lock().unwrap().push((ctx.key.clone(), ctx.value));
+            if ctx.key.starts_with(self.prefix) {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware_chain() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let api = UpdatesApi {
+            fetched: vec![Update::Single("Berlin".to_string(), 29)],
+            updates: vec![
+                Update::Single("Paris".to_string(), 30),
+                Update::Batch(vec![("London".to_string(), 25), ("Rome".to_string(), 20)]),
+            ],
+        };
+        let cache = StreamCache::builder()
+            .with_middleware(vec![
+                Arc::new(ToFahrenheit),
+                Arc::new(VetoPrefix {
+                    prefix: "L",
+                    seen: Arc::clone(&seen),
+                }),
+            ])
+            .build(api);
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(cache.get("Paris"), Some(86));
+        assert_eq!(cache.get("Rome"), Some(68));
+        assert_eq!(cache.get("London"), None);
+        assert_eq!(cache.get("Berlin"), Some(29));
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                ("Paris".to_string(), 86),
+                ("London".to_string(), 77),
+                ("Rome".to_string(), 68),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_manual_driver() {
+        let api = UpdatesApi {
+            fetched: vec![Update::Single("Berlin".to_string(), 29)],
+            updates: vec![
+                Update::Single("Paris".to_string(), 31),
+                Update::Single("Paris".to_string(), 32),
+                Update::Resync,
+                Update::Single("London".to_string(), 27),
+            ],
+        };
+        let (cache, mut driver) = StreamCache::new_manual(api);
+        assert!(cache.is_empty());
+
+        assert!(driver.poll_once().await);
+        assert_eq!(cache.snapshot(), hashmap! { "Berlin".to_string() => 29 });
+        assert!(driver.poll_once().await);
+        assert_eq!(cache.get("Paris"), Some(31));
+        assert!(driver.poll_once().await);
+        assert_eq!(cache.get("Paris"), Some(32));
+        assert!(driver.poll_once().await);
+        assert!(cache.is_empty());
+        assert!(driver.poll_once().await);
+        assert_eq!(cache.snapshot(), hashmap! { "Berlin".to_string() => 29 });
+        assert!(driver.poll_once().await);
+        assert_eq!(cache.len(), 2);
+        assert!(!driver.poll_once().await);
+        assert!(!driver.poll_once().await);
+    }
+
+    #[tokio::test]
+    async fn test_manual_driver_shares_the_pipeline() {
+        let api = UpdatesApi {
+            updates: vec![
+                Update::Single("Paris".to_string(), 30),
+                Update::Single("London".to_string(), 25),
+                Update::Single("Paris".to_string(), 20),
+            ],
+            ..Default::default()
+        };
+        let resolver: AsyncResolver = Arc::new(|_key: &str, existing: u64, incoming: u64| {
+            async move { existing.max(incoming) }.boxed()
+        });
+        let (cache, mut driver) = StreamCache::builder()
+            .skip_initial_fetch(true)
+            .conflict_policy(ConflictPolicy::Async(resolver))
+            .with_middleware(vec![
+                Arc::new(ToFahrenheit),
+                Arc::new(VetoPrefix {
+                    prefix: "L",
+                    seen: Arc::default(),
+                }),
+            ])
+            .build_manual(api);
+
+        for _ in 0..3 {
+            assert!(driver.poll_once().await);
+        }
+        assert_eq!(cache.snapshot(), hashmap! { "Paris".to_string() => 86 });
+    }
+
+    #[tokio::test]
+    async fn test_manual_driver_error_policy() {
+        let (cache, mut driver) = StreamCache::builder()
+            .skip_initial_fetch(true)
+            .subscription_error_policy(SubscriptionErrorPolicy::Reconnect)
+            .build_manual(FlakyStreamApi::default());
+
+        // Paris, the error, then Rome from the second subscription.
+        for _ in 0..3 {
+            assert!(driver.poll_once().await);
+        }
+        assert_eq!(cache.get("Paris"), Some(1));
+        assert_eq!(cache.get("Rome"), Some(3));
+
+        let (cache, mut driver) = StreamCache::builder()
+            .skip_initial_fetch(true)
+            .subscription_error_policy(SubscriptionErrorPolicy::Fail)
+            .build_manual(FlakyStreamApi::default());
+        assert!(driver.poll_once().await);
+        assert!(driver.poll_once().await);
+        assert!(!driver.poll_once().await);
+        assert_eq!(cache.get("Paris"), Some(1));
+        assert_eq!(
+            cache.last_error(),
+            Some(CacheError::Subscribe("connection reset".to_string()))
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_transaction_is_atomic() {
+        let cache = Arc::new(StreamCache::builder().build_empty());
+        cache.insert("Paris", 31).unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+
+        let reader = tokio::spawn({
+            let cache = Arc::clone(&cache);
+            let done = Arc::clone(&done);
+            async move {
+                while !done.load(Ordering::SeqCst) {
+                    let snapshot = cache.snapshot();
+                    assert!(
+                        snapshot == hashmap! { "Paris".to_string() => 31 }
+                            || snapshot == hashmap! { "Lutece".to_string() => 31 },
+                        "partial transaction: {snapshot:?}"
+                    );
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+        time::sleep(Duration::from_millis(10)).await;
+        cache
+            .transaction(vec![
+                Op::Remove("Paris".to_string()),
+                Op::Set("Lutece".to_string(), 31),
+            ])
+            .unwrap();
+        time::sleep(Duration::from_millis(10)).await;
+        done.store(true, Ordering::SeqCst);
+        reader.await.unwrap();
+        assert_eq!(cache.get("Lutece"), Some(31));
+
+        let bounded = StreamCache::builder()
+            .with_value_bounds(0, 50, OnViolation::Error)
+            .build_empty();
+        bounded.insert("Paris", 31).unwrap();
+        let result = bounded.transaction(vec![
+            Op::Remove("Paris".to_string()),
+            Op::Set("Lutece".to_string(), 99),
+        ]);
+        assert!(matches!(result, Err(CacheError::OutOfRange { .. })));
+        assert_eq!(bounded.get("Paris"), Some(31));
+    }
+
+    #[tokio::test]
+    async fn test_take_dirty() {
+        let api = UpdatesApi {
+            updates: vec![
+                Update::Single("Paris".to_string(), 31),
+                Update::Single("London".to_string(), 27),
+                Update::Single("Paris".to_string(), 32),
+            ],
+            ..Default::default()
+        };
+        let cache = StreamCache::builder().track_dirty(true).build(api);
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(
+            cache.take_dirty(),
+            Ok(vec!["London".to_string(), "Paris".to_string()])
+        );
+        assert_eq!(cache.take_dirty(), Ok(Vec::new()));
+
+        cache.insert("London", 27).unwrap();
+        assert_eq!(cache.take_dirty(), Ok(Vec::new()));
+        cache.insert("London", 28).unwrap();
+        assert_eq!(cache.take_dirty(), Ok(vec!["London".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn test_take_dirty_without_tracking_is_an_error() {
+        let cache = StreamCache::builder().build_empty();
+        cache.insert("Paris", 31).unwrap();
+        assert_eq!(cache.take_dirty(), Err(CacheError::DirtyTrackingOff));
+    }
+
+    #[tokio::test]
+    async fn test_write_priority() {
+        async fn run(priority: WritePriority) -> Option<u64> {
+            let api = MockApi {
+                fetched: hashmap! { "Paris".to_string() => 29 },
+                updates: vec![("Paris".to_string(), 32)],
+                ..Default::default()
+            };
+            let cache = StreamCache::builder()
+                .conflict_policy(ConflictPolicy::Custom(Arc::new(|_, _, incoming| incoming)))
+                .write_priority(priority)
+                .build(api);
+            time::sleep(Duration::from_millis(50)).await;
+            cache.get("Paris")
+        }
+
+        for _ in 0..10 {
+            assert_eq!(run(WritePriority::FetchFirst).await, Some(32));
+            assert_eq!(run(WritePriority::SubscribeFirst).await, Some(32));
+        }
+    }
+
+    /// Streams `update` straight away, then nothing; `fetch` answers once
+    /// `release` is notified.
+    #[derive(Clone)]
+    struct SlowFetchApi {
+        update: (City, Temperature),
+        release: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl Api for SlowFetchApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            self.release.notified().await;
+            Ok(hashmap! { self.update.0.clone() => 29 })
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            futures::stream::iter([Ok(self.update.clone())])
+                .chain(futures::stream::pending())
+                .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_first_keeps_streamed_value_over_late_fetch() {
+        async fn run(priority: WritePriority) -> Option<u64> {
+            let api = SlowFetchApi {
+                update: ("Paris".to_string(), 32),
+                release: Default::default(),
+            };
+            let release = Arc::clone(&api.release);
+            let cache = StreamCache::builder()
+                .conflict_policy(ConflictPolicy::Custom(Arc::new(|_, _, incoming| incoming)))
+                .write_priority(priority)
+                .build(api);
+            time::sleep(Duration::from_millis(20)).await;
+            release.notify_one();
+            cache.wait_ready().await;
+            cache.get("Paris")
+        }
+
+        assert_eq!(run(WritePriority::Fair).await, Some(29));
+        assert_eq!(run(WritePriority::SubscribeFirst).await, Some(32));
+    }
+
+    /// Fetches Paris, but never streams anything.
+    #[derive(Clone)]
+    struct QuietApi;
+
+    #[async_trait]
+    impl Api for QuietApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            Ok(hashmap! { "Paris".to_string() => 29 })
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            futures::stream::pending().boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_first_fetches_on_a_quiet_stream() {
+        let cache = StreamCache::builder()
+            .write_priority(WritePriority::SubscribeFirst)
+            .build(QuietApi);
+
+        time::timeout(Duration::from_secs(1), cache.wait_ready())
+            .await
+            .expect("wait_ready resolves without any streamed update");
+        assert_eq!(cache.get("Paris"), Some(29));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_with_read() {
+        let cache = StreamCache::builder()
+            .initial(hashmap! {
+                "Berlin".to_string() => 29,
+                "London".to_string() => 27,
+                "Paris".to_string() => 31,
+            })
+            .ttl(Duration::from_secs(5))
+            .build_empty();
+        time::advance(Duration::from_secs(3)).await;
+        cache.insert("Rome", 35).unwrap();
+
+        let above = |store: &LiveView| store.iter().filter(|(_, value)| *value >= 29).count();
+        assert_eq!(cache.with_read(above), 3);
+        time::advance(Duration::from_secs(3)).await;
+        assert_eq!(cache.with_read(above), 1);
+        assert_eq!(cache.with_read(|store| store.get("Paris")), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_with_read_does_not_sweep() {
+        let expired = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&expired);
+        let cache = StreamCache::builder()
+            .ttl(Duration::from_secs(5))
+            .on_expire(Arc::new(move |_, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }))
+            .build_empty();
+        cache.insert("Paris", 31).unwrap();
+        time::advance(Duration::from_secs(6)).await;
+
+        assert!(cache.with_read(|store| store.is_empty()));
+        assert_eq!(expired.load(Ordering::SeqCst), 0);
+        assert_eq!(cache.get("Paris"), None);
+        assert_eq!(expired.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_per_key_throttle_coalesces_only_throttled_keys() {
+        let cache = StreamCache::builder()
+            .with_per_key_throttle(Arc::new(|key| {
+                (key == "Paris").then_some(Duration::from_secs(1))
+            }))
+            .build_empty();
+        let watch = cache.watch();
+
+        assert_eq!(
+            cache.insert_with_outcome("Paris", 20),
+            Ok(InsertOutcome::Inserted)
+        );
+        for temperature in [21, 22] {
+            assert_eq!(
+                cache.insert_with_outcome("Paris", temperature),
+                Ok(InsertOutcome::Deferred)
+            );
+        }
+        for temperature in [10, 11, 12] {
+            cache.insert("London", temperature).unwrap();
+            assert_eq!(cache.get("London"), Some(temperature));
+        }
+        assert_eq!(cache.get("Paris"), Some(20));
+
+        time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(cache.get("Paris"), Some(22));
+
+        drop(cache);
+        let paris: Vec<_> = watch
+            .filter(|event| future::ready(event.key == "Paris"))
+            .map(|event| event.new)
+            .collect()
+            .await;
+        assert_eq!(paris, vec![20, 22]);
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[tokio::test]
+    async fn test_otel_spans_are_parented_to_the_context() {
+        use opentelemetry::trace::{Span as _, TraceContextExt, Tracer, TracerProvider as _};
+        use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+        use opentelemetry_sdk::trace::TracerProvider;
+
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        opentelemetry::global::set_tracer_provider(provider.clone());
+        let root = provider.tracer("test").start("root");
+        let root_id = root.span_context().span_id();
+        let context = opentelemetry::Context::current_with_span(root);
+        let cache = StreamCache::builder()
+            .with_otel_context(context)
+            .build(TestApi::default());
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get("Berlin"), Some(29));
+        cache.shutdown_graceful().await;
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let named = |name| spans.iter().filter(move |span| span.name == name);
+        let fetch: Vec<_> = named("fetch").collect();
+        let subscribe: Vec<_> = named("subscribe").collect();
+        assert_eq!(fetch.len(), 1);
+        assert_eq!(subscribe.len(), 1);
+        assert_eq!(fetch[0].parent_span_id, root_id);
+        assert_eq!(subscribe[0].parent_span_id, root_id);
+        let trace_id = subscribe[0].span_context.trace_id();
+        assert_eq!(fetch[0].span_context.trace_id(), trace_id);
+        let applied: Vec<_> = named("apply").collect();
+        assert_eq!(applied.len(), 2);
+        assert!(applied
+            .iter()
+            .all(|span| span.parent_span_id == subscribe[0].span_context.span_id()));
+        // The subscription span ends last, after every update in it.
+        assert_eq!(
+            spans.last().map(|span| &span.name),
+            Some(&subscribe[0].name)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_filter_returns_matching_entries() {
+        let cache = StreamCache::builder().build_empty();
+        cache.insert("Berlin", 29).unwrap();
+        cache.insert("London", 30).unwrap();
+        cache.insert("Paris", 31).unwrap();
+
+        assert_eq!(
+            cache.filter(|_, temperature| temperature >= 30),
+            hashmap! {
+                "London".to_string() => 30,
+                "Paris".to_string() => 31,
+            }
+        );
+        assert!(cache.filter(|city, _| city == "Rome").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pinned_key_survives_eviction() {
+        let cache = StreamCache::builder().capacity(2).build_empty();
+        cache.pin("Paris");
+        for city in ["Paris", "London", "Berlin", "Rome"] {
+            cache.insert(city, 30).unwrap();
+        }
+        assert_eq!(cache.get("Paris"), Some(30));
+        assert_eq!(cache.get("Rome"), Some(30));
+        assert_eq!(cache.len(), 2);
+
+        cache.unpin("Paris");
+        cache.get("Rome");
+        cache.insert("Madrid", 30).unwrap();
+        assert_eq!(cache.get("Paris"), None);
+    }
+
+    #[tokio::test]
+    async fn test_pins_outside_capacity() {
+        let cache = StreamCache::builder()
+            .capacity(2)
+            .pins_count_toward_capacity(false)
+            .build_empty();
+        cache.pin("Paris");
+        for city in ["Paris", "London", "Berlin", "Rome"] {
+            cache.insert(city, 30).unwrap();
+        }
+        assert_eq!(cache.get("Paris"), Some(30));
+        assert_eq!(cache.get("London"), None);
+        assert_eq!(cache.len(), 3);
+    }
+
+    /// Serves its snapshot as two pages, and streams nothing.
+    #[derive(Clone)]
+    struct PagedApi;
+
+    #[async_trait]
+    impl Api for PagedApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            unreachable!("the cache fetches page by page")
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            futures::stream::pending().boxed()
+        }
+
+        async fn fetch_page(
+            &self,
+            cursor: Option<String>,
+        ) -> Result<(HashMap<City, Temperature>, Option<String>), CacheError> {
+            match cursor.as_deref() {
+                None => Ok((
+                    hashmap! { "Berlin".to_string() => 29, "London".to_string() => 27 },
+                    Some("page-2".to_string()),
+                )),
+                Some("page-2") => Ok((hashmap! { "Paris".to_string() => 31 }, None)),
+                Some(cursor) => Err(CacheError::Fetch(format!("unknown cursor {cursor}"))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_initial_fetch_follows_pages() {
+        let cache = StreamCache::new(PagedApi);
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            cache.snapshot(),
+            hashmap! {
+                "Berlin".to_string() => 29,
+                "London".to_string() => 27,
+                "Paris".to_string() => 31,
+            }
+        );
+        assert_eq!(cache.last_error(), None);
+        assert_eq!(fetch_once(&PagedApi).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_windowed_hit_ratio_forgets_old_reads() {
+        let cache = StreamCache::builder()
+            .hit_ratio_window(Duration::from_secs(10))
+            .build_empty();
+        cache.insert("Paris", 31).unwrap();
+        for _ in 0..3 {
+            cache.get("Paris");
+        }
+        cache.get("London");
+        assert_eq!(cache.windowed_hit_ratio(), 0.75);
+
+        time::sleep(Duration::from_secs(5)).await;
+        cache.get("London");
+        cache.get("Berlin");
+        assert_eq!(cache.windowed_hit_ratio(), 0.5);
+
+        // The first bucket has left the window; the two misses remain.
+        time::sleep(Duration::from_secs(6)).await;
+        assert_eq!(cache.windowed_hit_ratio(), 0.0);
+        cache.get("Paris");
+        assert_eq!(cache.windowed_hit_ratio(), 1.0 / 3.0);
+
+        time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(cache.windowed_hit_ratio(), 0.0);
+        assert_eq!(cache.metrics().hits, 4);
+    }
+
+    /// Panics in the first `subscribe` call, then streams one update.
+    #[derive(Clone, Default)]
+    struct PanickyApi {
+        subscribes: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Api for PanickyApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            Ok(HashMap::new())
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            if self.subscribes.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("subscribe blew up");
+            }
+            futures::stream::iter([Ok(("London".to_string(), 27))])
+                .chain(futures::stream::pending())
+                .boxed()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_subscribe_panic_reconnects() {
+        let api = PanickyApi::default();
+        let cache = StreamCache::new(api.clone());
+        time::sleep(Duration::from_secs(1)).await;
+
+        assert_eq!(cache.get("London"), Some(27));
+        assert_eq!(api.subscribes.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            cache.last_error(),
+            Some(CacheError::SubscribePanic("subscribe blew up".to_string()))
+        );
+    }
+
+    /// Panics in every `subscribe` call.
+    #[derive(Clone, Default)]
+    struct BrokenSubscribeApi {
+        subscribes: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Api for BrokenSubscribeApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            Ok(HashMap::new())
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            self.subscribes.fetch_add(1, Ordering::SeqCst);
+            panic!("subscribe blew up");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resubscribe_backs_off() {
+        let api = BrokenSubscribeApi::default();
+        let _cache = StreamCache::new(api.clone());
+        time::sleep(Duration::from_secs(10)).await;
+
+        // Subscribes at 0s, then after 0.1, 0.2, 0.4, 0.8, 1.6 and 3.2s
+        // more; the next would be 6.4s after that.
+        assert_eq!(api.subscribes.load(Ordering::SeqCst), 7);
+
+        // 6.4, 12.8 and 25.6s later, then every 30s once the delay is capped.
+        time::sleep(Duration::from_secs(120)).await;
+        assert_eq!(api.subscribes.load(Ordering::SeqCst), 12);
+    }
+
+    #[tokio::test]
+    async fn test_set_api_keeps_data_and_switches_source() {
+        let cache = StreamCache::new(TestApi::default());
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get("Berlin"), Some(29));
+
+        let cache = cache.set_api(MockApi {
+            updates: vec![("Rome".to_string(), 25)],
+            ..Default::default()
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            cache.snapshot(),
+            hashmap! {
+                "Berlin".to_string() => 29,
+                "London".to_string() => 27,
+                "Paris".to_string() => 32,
+                "Rome".to_string() => 25,
+            }
+        );
+        assert_eq!(cache.api().updates, vec![("Rome".to_string(), 25)]);
+
+        // The old worker exited, so the cache finishes with the new stream.
+        let joined = time::timeout(Duration::from_secs(1), cache.join()).await;
+        assert_eq!(joined, Ok(Ok(())));
+    }
+
+    /// Streams one update, but its fetch never resolves.
+    #[derive(Clone)]
+    struct HungFetchApi;
+
+    #[async_trait]
+    impl Api for HungFetchApi {
+        async fn fetch(&self) -> Result<HashMap<City, Temperature>, String> {
+            futures::future::pending().await
+        }
+
+        async fn subscribe(&self) -> BoxStream<Result<(City, Temperature), String>> {
+            futures::stream::iter([Ok(("London".to_string(), 27))])
+                .chain(futures::stream::pending())
+                .boxed()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_set_api_abandons_a_hung_fetch() {
+        let cache = StreamCache::new(HungFetchApi);
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get("London"), Some(27));
+
+        let cache = cache.set_api(MockApi {
+            fetched: hashmap! { "Berlin".to_string() => 29 },
+            ..Default::default()
+        });
+        let ready = time::timeout(Duration::from_secs(1), cache.wait_ready()).await;
+        assert!(ready.is_ok());
+        assert_eq!(cache.get("Berlin"), Some(29));
+        assert_eq!(cache.get("London"), Some(27));
+        assert_eq!(cache.api().fetch_calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_write_deadline_freezes_the_cache() {
+        let (on_error, reported) = error_recorder();
+        let cache = StreamCache::builder()
+            .with_write_deadline(Instant::now() + Duration::from_secs(10))
+            .on_error(on_error)
+            .build(MockApi {
+                updates: vec![("London".to_string(), 27), ("London".to_string(), 28)],
+                interval: Duration::from_secs(6),
+                ..Default::default()
+            });
+        cache.insert("Paris", 30).unwrap();
+
+        time::sleep(Duration::from_secs(15)).await;
+        assert_eq!(
+            cache.insert("Paris", 31),
+            Err(CacheError::PastDeadline {
+                key: "Paris".to_string(),
+                value: 31,
+            })
+        );
+        cache.replace_all(hashmap! { "Paris".to_string() => 40 });
+        assert_eq!(
+            cache.transaction(vec![Op::Set("Paris".to_string(), 41)]),
+            Err(CacheError::PastDeadline {
+                key: "Paris".to_string(),
+                value: 41,
+            })
+        );
+        cache.with_entry("Paris", |value| *value.unwrap() = 42);
+        assert_eq!(
+            cache
+                .shared_store()
+                .lock()
+                .unwrap()
+                .insert("Paris".to_string(), 43),
+            Err(CacheError::PastDeadline {
+                key: "Paris".to_string(),
+                value: 43,
+            })
+        );
+        assert_eq!(cache.get("Paris"), Some(30));
+        assert_eq!(cache.get("London"), Some(27));
+        let past = |key: &str, value| {
+            (
+                CacheError::PastDeadline {
+                    key: key.to_string(),
+                    value,
+                },
+                1,
+            )
+        };
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec![past("London", 28), past("Paris", 42)]
+        );
     }
 }